@@ -20,33 +20,49 @@
 //!
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::{
-    blockdata::script::Builder,
+    blockdata::script::{Builder, Instruction},
     blockdata::{
-        opcodes::all,
+        opcodes::{self, all},
         transaction::{SigHashType, TxOut},
     },
+    consensus::encode::serialize,
     network::constants::Network,
     util::bip143,
-    util::bip32::{ChildNumber, ExtendedPrivKey},
+    util::bip32::{ChainCode, ChildNumber, DerivationPath, ExtendedPrivKey},
     Address, OutPoint, PrivateKey, PublicKey, Script, Transaction,
 };
-use bitcoin_hashes::{hash160, Hash};
+use bitcoin_hashes::{hash160, sha256d, Hash};
 use crypto::{
-    aes, blockmodes, buffer,
+    aead::{AeadDecryptor, AeadEncryptor},
+    aes,
+    aes_gcm::AesGcm,
+    blockmodes, buffer,
     buffer::{BufferResult, ReadBuffer, WriteBuffer},
     digest::Digest,
+    scrypt,
     sha2::Sha256,
+    symmetriccipher,
+    util::secure_memset,
 };
 use rand::{thread_rng, RngCore};
+use secp256k1::Signature;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::HashMap,
+    ops::Deref,
+    sync::atomic::{compiler_fence, Ordering},
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use bip47::PaymentCode;
 use context::SecpContext;
 use error::Error;
+use satisfier::{Satisfier, SingleSignature, WitnessElement};
+use signer::Signer;
 use sss::{ShamirSecretSharing, Share};
+use taproot;
+use template::ScriptTemplate;
 
 use crate::mnemonic::Mnemonic;
 
@@ -58,12 +74,78 @@ pub enum MasterKeyEntropy {
     Paranoid = 64,
 }
 
+/// version of the storage format of a master account and its accounts
+pub const STORAGE_VERSION: u32 = 1;
+
 /// A masterAccount is the root of an account hierarchy
 pub struct MasterAccount {
     master_public: ExtendedPubKey,
     encrypted: Vec<u8>,
     accounts: HashMap<(u32, u32), Account>,
+    /// account of each pubkey script of instantiated keys, the first one of a script
+    scripts: HashMap<Script, (u32, u32)>,
+    /// number of keys of each account in scripts
+    indexed: HashMap<(u32, u32), usize>,
+    birth: u64,
+}
+
+/// storage format of a master account as written
+#[derive(Serialize)]
+struct MasterAccountRef<'a> {
+    version: u32,
+    master_public: &'a ExtendedPubKey,
+    encrypted: &'a Vec<u8>,
+    birth: u64,
+    accounts: Vec<&'a Account>,
+}
+
+/// storage format of a master account as read
+#[derive(Deserialize)]
+struct StoredMasterAccount {
+    version: u32,
+    master_public: ExtendedPubKey,
+    encrypted: Vec<u8>,
     birth: u64,
+    accounts: Vec<Account>,
+}
+
+impl Serialize for MasterAccount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut accounts = self.accounts.iter().collect::<Vec<_>>();
+        accounts.sort_by_key(|(k, _)| **k);
+        MasterAccountRef {
+            version: STORAGE_VERSION,
+            master_public: &self.master_public,
+            encrypted: &self.encrypted,
+            birth: self.birth,
+            accounts: accounts.into_iter().map(|(_, a)| a).collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MasterAccount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MasterAccount, D::Error> {
+        let stored = StoredMasterAccount::deserialize(deserializer)?;
+        if stored.version != STORAGE_VERSION {
+            return Err(de::Error::custom(format!(
+                "unknown master account storage version {}",
+                stored.version
+            )));
+        }
+        let mut master = MasterAccount::from_encrypted(
+            stored.encrypted.as_slice(),
+            stored.master_public,
+            stored.birth,
+        );
+        // accounts of a master share one context
+        let context = deserialized_context();
+        for mut account in stored.accounts {
+            account.context = context.clone();
+            master.add_account(account);
+        }
+        Ok(master)
+    }
 }
 
 impl MasterAccount {
@@ -95,6 +177,8 @@ impl MasterAccount {
             master_public: public_master_key,
             encrypted,
             accounts: HashMap::new(),
+            scripts: HashMap::new(),
+            indexed: HashMap::new(),
             birth,
         }
     }
@@ -105,6 +189,8 @@ impl MasterAccount {
             master_public: public_master_key,
             encrypted: Vec::new(),
             accounts: HashMap::new(),
+            scripts: HashMap::new(),
+            indexed: HashMap::new(),
             birth,
         }
     }
@@ -147,6 +233,8 @@ impl MasterAccount {
             master_public: public_master_key,
             encrypted,
             accounts: HashMap::new(),
+            scripts: HashMap::new(),
+            indexed: HashMap::new(),
             birth,
         })
     }
@@ -161,6 +249,33 @@ impl MasterAccount {
         Ok(seed)
     }
 
+    /// re-encrypt the seed with a new passphrase
+    /// the old passphrase is verified against the master public key before anything changes
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<(), Error> {
+        let seed = self.seed(self.master_public.network, old)?;
+        self.encrypted = seed.encrypt(new)?;
+        Ok(())
+    }
+
+    /// unlock for signing
+    /// a seed encrypted in the legacy format is re-encrypted in the current one
+    pub fn unlock(&mut self, passphrase: &str) -> Result<Unlocker, Error> {
+        let unlocker = Unlocker::new_for_master(self, passphrase)?;
+        self.upgrade_encryption(passphrase)?;
+        Ok(unlocker)
+    }
+
+    /// re-encrypt a seed of the legacy format in the current one
+    /// returns true if upgraded, the master should then be stored again
+    pub fn upgrade_encryption(&mut self, passphrase: &str) -> Result<bool, Error> {
+        if self.encrypted.is_empty() || !Seed::is_legacy(self.encrypted.as_slice()) {
+            return Ok(false);
+        }
+        let seed = self.seed(self.master_public.network, passphrase)?;
+        self.encrypted = seed.encrypt(passphrase)?;
+        Ok(true)
+    }
+
     pub fn master_public(&self) -> &ExtendedPubKey {
         &self.master_public
     }
@@ -178,6 +293,8 @@ impl MasterAccount {
     }
 
     pub fn get_mut(&mut self, account: (u32, u32)) -> Option<&mut Account> {
+        // keys added through an account borrowed before
+        self.index_stale();
         self.accounts.get_mut(&account)
     }
 
@@ -196,32 +313,150 @@ impl MasterAccount {
                         kix,
                         tweak,
                         csv,
+                        imported: a.imported,
+                        path: a.path.clone(),
                     },
                 )
             })
         })
     }
 
+    /// key derivation of a pubkey script of any account
+    pub fn get_derivation(&self, script_pubkey: &Script) -> Option<KeyDerivation> {
+        let (an, sub) = self.scripts.get(script_pubkey).cloned().or_else(|| {
+            // keys added through get_mut since their account was indexed
+            self.accounts
+                .iter()
+                .find(|(k, a)| {
+                    a.instantiated.len() > self.indexed.get(k).cloned().unwrap_or(0)
+                        && a.get_key_by_script(script_pubkey).is_some()
+                })
+                .map(|(k, _)| *k)
+        })?;
+        let a = self.accounts.get(&(an, sub))?;
+        a.get_key_by_script(script_pubkey)
+            .map(|(kix, i)| KeyDerivation {
+                account: an,
+                sub,
+                kix,
+                tweak: i.tweak.clone(),
+                csv: i.csv,
+                imported: a.imported,
+                path: a.path.clone(),
+            })
+    }
+
+    pub fn remove_account(&mut self, account: (u32, u32)) -> Option<Account> {
+        let removed = self.accounts.remove(&account)?;
+        self.indexed.remove(&account);
+        for instantiated in removed.instantiated.iter() {
+            let script_pubkey = instantiated.address.script_pubkey();
+            if self.scripts.get(&script_pubkey) != Some(&account) {
+                continue;
+            }
+            // an other account might have the same script
+            match self
+                .accounts
+                .iter()
+                .find(|(_, a)| a.get_key_by_script(&script_pubkey).is_some())
+            {
+                Some((other, _)) => {
+                    self.scripts.insert(script_pubkey, *other);
+                }
+                None => {
+                    self.scripts.remove(&script_pubkey);
+                }
+            }
+        }
+        Some(removed)
+    }
+
     pub fn add_account(&mut self, account: Account) {
-        self.accounts.insert(
-            (account.account_number, account.sub_account_number),
-            account,
-        );
+        let key = (account.account_number, account.sub_account_number);
+        self.remove_account(key);
+        self.accounts.insert(key, account);
+        self.index_account(key);
+    }
+
+    /// look ahead in an account, keeping the script index of the master
+    /// see Account::do_look_ahead
+    pub fn do_look_ahead(
+        &mut self,
+        account: (u32, u32),
+        seen: Option<u32>,
+    ) -> Result<Vec<(u32, Script)>, Error> {
+        let new = self
+            .accounts
+            .get_mut(&account)
+            .ok_or(Error::Unsupported("no such account"))?
+            .do_look_ahead(seen)?;
+        self.index_account(account);
+        Ok(new)
+    }
+
+    /// index scripts of keys an account got since it was indexed
+    fn index_account(&mut self, key: (u32, u32)) {
+        if let Some(account) = self.accounts.get(&key) {
+            let indexed = self.indexed.entry(key).or_insert(0);
+            for instantiated in account.instantiated[*indexed..].iter() {
+                self.scripts
+                    .entry(instantiated.address.script_pubkey())
+                    .or_insert(key);
+            }
+            *indexed = account.instantiated.len();
+        }
+    }
+
+    fn index_stale(&mut self) {
+        let stale = self
+            .accounts
+            .iter()
+            .filter(|(k, a)| a.instantiated.len() > self.indexed.get(k).cloned().unwrap_or(0))
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        for key in stale {
+            self.index_account(key);
+        }
     }
 
-    pub fn sign<R>(
+    pub fn sign<R, S>(
         &self,
         transaction: &mut Transaction,
         hash_type: SigHashType,
         resolver: &R,
-        unlocker: &mut Unlocker,
+        signer: &mut S,
+    ) -> Result<usize, Error>
+    where
+        R: Fn(&OutPoint) -> Option<TxOut>,
+        S: Signer + ?Sized,
+    {
+        self.sign_with_satisfier(transaction, hash_type, resolver, signer, &SingleSignature)
+    }
+
+    /// sign a transaction with all accounts, witnesses of P2WSH scripts are built as
+    /// the satisfier tells
+    pub fn sign_with_satisfier<R, S, W>(
+        &self,
+        transaction: &mut Transaction,
+        hash_type: SigHashType,
+        resolver: &R,
+        signer: &mut S,
+        satisfier: &W,
     ) -> Result<usize, Error>
     where
         R: Fn(&OutPoint) -> Option<TxOut>,
+        S: Signer + ?Sized,
+        W: Satisfier + ?Sized,
     {
+        // public keys that signatures for other keys are checked against
+        let keys = |address_type: AccountAddressType, derivation: &KeyDerivation| {
+            self.get((derivation.account, derivation.sub))
+                .and_then(|a| a.public_key(address_type, derivation))
+        };
         let mut n_signatures = 0;
         for (_, a) in self.accounts.iter() {
-            n_signatures += a.sign(transaction, hash_type, resolver, unlocker)?;
+            n_signatures +=
+                a.sign_with_keys(transaction, hash_type, resolver, signer, satisfier, &keys)?;
         }
         Ok(n_signatures)
     }
@@ -245,6 +480,49 @@ pub struct Unlocker {
             >,
         ),
     >,
+    /// limits of a session, the master private key is wiped in a session
+    session: Option<Session>,
+    /// called before a private key is exported
+    export_audit: Option<ExportAudit>,
+}
+
+/// hook called with the address type and derivation of a private key before it is exported
+/// the export is refused if it returns an error
+pub type ExportAudit =
+    Box<dyn FnMut(AccountAddressType, &KeyDerivation) -> Result<(), Error> + Send>;
+
+/// accounts and limits an unlocker session is restricted to
+#[derive(Clone, Debug, Default)]
+pub struct UnlockerScope {
+    /// (address type, account number, sub account number) of accounts in scope
+    pub accounts: Vec<(AccountAddressType, u32, u32)>,
+    /// the session expires this long after its creation
+    pub expires_after: Option<Duration>,
+    /// the session expires after this many signatures
+    pub max_signatures: Option<u32>,
+    /// custom derivation paths of accounts in scope
+    pub paths: Vec<DerivationPath>,
+}
+
+struct Session {
+    keys: HashMap<(AccountAddressType, u32, u32), ExtendedPrivKey>,
+    path_keys: Vec<(DerivationPath, ExtendedPrivKey)>,
+    deadline: Option<SystemTime>,
+    signatures_left: Option<u32>,
+}
+
+impl Session {
+    fn check(&self) -> Result<(), Error> {
+        if let Some(deadline) = self.deadline {
+            if SystemTime::now() >= deadline {
+                return Err(Error::Session("session expired"));
+            }
+        }
+        if self.signatures_left == Some(0) {
+            return Err(Error::Session("session signature limit reached"));
+        }
+        Ok(())
+    }
 }
 
 impl Unlocker {
@@ -272,9 +550,49 @@ impl Unlocker {
             network,
             context,
             cached: HashMap::new(),
+            session: None,
+            export_audit: None,
         })
     }
 
+    /// an unlocker restricted to the accounts and limits of scope
+    /// keys of the accounts in scope are derived in advance, then the master private key is wiped
+    pub fn new_session(
+        master: &MasterAccount,
+        passphrase: &str,
+        scope: &UnlockerScope,
+    ) -> Result<Unlocker, Error> {
+        let mut unlocker = Self::new_for_master(master, passphrase)?;
+        let mut keys = HashMap::new();
+        for (address_type, account, sub_account) in &scope.accounts {
+            keys.insert(
+                (*address_type, *account, *sub_account),
+                unlocker.sub_account_key(*address_type, *account, *sub_account)?,
+            );
+        }
+        let mut path_keys = Vec::new();
+        for path in &scope.paths {
+            path_keys.push((path.clone(), unlocker.path_key(path)?));
+        }
+        unlocker.lock();
+        wipe_extended_private(&mut unlocker.master_private);
+        unlocker.session = Some(Session {
+            keys,
+            path_keys,
+            deadline: scope.expires_after.map(|d| SystemTime::now() + d),
+            signatures_left: scope.max_signatures,
+        });
+        Ok(unlocker)
+    }
+
+    /// true if restricted to a scope
+    pub fn is_session(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// unlock a master without changing it
+    /// a seed encrypted in the legacy format stays so, prefer MasterAccount::unlock
+    /// or call MasterAccount::upgrade_encryption
     pub fn new_for_master(master: &MasterAccount, passphrase: &str) -> Result<Unlocker, Error> {
         Self::new(
             master.encrypted(),
@@ -284,8 +602,21 @@ impl Unlocker {
         )
     }
 
+    /// the master private key
+    /// panics in a session, that wiped it, use try_master_private there
     pub fn master_private(&self) -> &ExtendedPrivKey {
-        &self.master_private
+        self.try_master_private()
+            .expect("master private key is not available in a session")
+    }
+
+    /// the master private key, not available in a session
+    pub fn try_master_private(&self) -> Result<&ExtendedPrivKey, Error> {
+        if self.session.is_some() {
+            return Err(Error::Session(
+                "master private key is not available in a session",
+            ));
+        }
+        Ok(&self.master_private)
     }
 
     pub fn sub_account_key(
@@ -294,6 +625,14 @@ impl Unlocker {
         account: u32,
         sub_account: u32,
     ) -> Result<ExtendedPrivKey, Error> {
+        if let Some(ref session) = self.session {
+            session.check()?;
+            return session
+                .keys
+                .get(&(address_type, account, sub_account))
+                .cloned()
+                .ok_or(Error::Session("account is out of session scope"));
+        }
         let by_purpose = self.cached.entry(address_type).or_insert((
             self.context.private_child(
                 &self.master_private,
@@ -303,11 +642,7 @@ impl Unlocker {
             )?,
             HashMap::new(),
         ));
-        let coin_type = match self.network {
-            Network::Bitcoin => 0,
-            Network::Testnet => 1,
-            Network::Regtest => 1,
-        };
+        let coin_type = coin_type(self.network);
         let by_coin_type = by_purpose.1.entry(coin_type).or_insert((
             self.context
                 .private_child(&by_purpose.0, ChildNumber::Hardened { index: coin_type })?,
@@ -318,9 +653,32 @@ impl Unlocker {
                 .private_child(&by_coin_type.0, ChildNumber::Hardened { index: account })?,
             HashMap::new(),
         ));
+        let account_key = match address_type.script_type() {
+            Some(script_type) => by_account.1.entry(script_type).or_insert(
+                self.context
+                    .private_child(&by_account.0, ChildNumber::Hardened { index: script_type })?,
+            ),
+            None => &by_account.0,
+        };
         Ok(self
             .context
-            .private_child(&by_account.0, ChildNumber::Normal { index: sub_account })?)
+            .private_child(account_key, ChildNumber::Normal { index: sub_account })?)
+    }
+
+    /// the key at a custom derivation path of an account
+    pub fn path_key(&mut self, path: &DerivationPath) -> Result<ExtendedPrivKey, Error> {
+        if let Some(ref session) = self.session {
+            session.check()?;
+            return session
+                .path_keys
+                .iter()
+                .find(|(p, _)| p == path)
+                .map(|(_, k)| *k)
+                .ok_or(Error::Session("path is out of session scope"));
+        }
+        let context = self.context.clone();
+        path.into_iter()
+            .try_fold(self.master_private, |k, c| context.private_child(&k, *c))
     }
 
     pub fn unlock(
@@ -332,9 +690,39 @@ impl Unlocker {
         tweak: Option<Vec<u8>>,
     ) -> Result<PrivateKey, Error> {
         let sub_account_key = self.sub_account_key(address_type, account, sub_account)?;
+        self.unlock_child(&sub_account_key, index, tweak)
+    }
+
+    /// the private key of a derivation, at its custom path if it has one
+    pub fn unlock_derivation(
+        &mut self,
+        address_type: AccountAddressType,
+        derivation: &KeyDerivation,
+    ) -> Result<PrivateKey, Error> {
+        match derivation.path {
+            Some(ref path) => {
+                let path_key = self.path_key(path)?;
+                self.unlock_child(&path_key, derivation.kix, derivation.tweak.clone())
+            }
+            None => self.unlock(
+                address_type,
+                derivation.account,
+                derivation.sub,
+                derivation.kix,
+                derivation.tweak.clone(),
+            ),
+        }
+    }
+
+    fn unlock_child(
+        &mut self,
+        sub_account_key: &ExtendedPrivKey,
+        index: u32,
+        tweak: Option<Vec<u8>>,
+    ) -> Result<PrivateKey, Error> {
         let mut key = self
             .context
-            .private_child(&sub_account_key, ChildNumber::Normal { index })?
+            .private_child(sub_account_key, ChildNumber::Normal { index })?
             .private_key;
         if let Some(tweak) = tweak {
             self.context.tweak_add(&mut key, tweak.as_slice())?;
@@ -342,14 +730,206 @@ impl Unlocker {
         Ok(key)
     }
 
+    /// encrypt an imported private key of an account
+    /// with AES256-GCM keyed by the private key of the account, authenticating its public key
+    pub fn encrypt_imported(
+        &mut self,
+        address_type: AccountAddressType,
+        account: u32,
+        sub_account: u32,
+        key: &PrivateKey,
+    ) -> Result<Vec<u8>, Error> {
+        let mut cipher_key = self.imported_cipher_key(address_type, account, sub_account)?;
+        let public = self.context.public_from_private(key).to_bytes();
+        let mut nonce = [0u8; 12];
+        thread_rng().fill_bytes(&mut nonce);
+        let mut cipher = AesGcm::new(aes::KeySize::KeySize256, &cipher_key, &nonce, &public);
+        let mut ciphertext = [0u8; 32];
+        let mut tag = [0u8; IMPORTED_KEY_TAG];
+        cipher.encrypt(&key.key[..], &mut ciphertext, &mut tag);
+        secure_memset(&mut cipher_key, 0);
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend_from_slice(&ciphertext);
+        encrypted.extend_from_slice(&tag);
+        Ok(encrypted)
+    }
+
+    /// decrypt an imported private key of an account
+    pub fn decrypt_imported(
+        &mut self,
+        address_type: AccountAddressType,
+        account: u32,
+        sub_account: u32,
+        public: &PublicKey,
+        encrypted: &[u8],
+    ) -> Result<PrivateKey, Error> {
+        if encrypted.len() != 12 + 32 + IMPORTED_KEY_TAG {
+            return Err(Error::SymmetricCipherError(
+                symmetriccipher::SymmetricCipherError::InvalidLength,
+            ));
+        }
+        let mut cipher_key = self.imported_cipher_key(address_type, account, sub_account)?;
+        let mut cipher = AesGcm::new(
+            aes::KeySize::KeySize256,
+            &cipher_key,
+            &encrypted[..12],
+            &public.to_bytes(),
+        );
+        let mut decrypted = [0u8; 32];
+        let authentic = cipher.decrypt(&encrypted[12..44], &mut decrypted, &encrypted[44..]);
+        secure_memset(&mut cipher_key, 0);
+        let key = if authentic {
+            secp256k1::key::SecretKey::from_slice(&decrypted).map_err(Error::from)
+        } else {
+            Err(Error::Passphrase)
+        };
+        secure_memset(&mut decrypted, 0);
+        Ok(PrivateKey {
+            compressed: public.compressed,
+            network: self.network,
+            key: key?,
+        })
+    }
+
+    /// count a signature against the limit of a session
+    pub(crate) fn count_signature(&mut self) {
+        if let Some(ref mut session) = self.session {
+            if let Some(ref mut left) = session.signatures_left {
+                *left = left.saturating_sub(1);
+            }
+        }
+    }
+
+    /// key of an account of imported keys at m / purpose' / coin_type' / account' / sub / 0'
+    /// apart from the keys of an account of derived keys with the same number
+    pub fn imported_account_key(
+        &mut self,
+        address_type: AccountAddressType,
+        account: u32,
+        sub_account: u32,
+    ) -> Result<ExtendedPrivKey, Error> {
+        let mut sub_account_key = self.sub_account_key(address_type, account, sub_account)?;
+        let key = self.context.private_child(
+            &sub_account_key,
+            ChildNumber::Hardened {
+                index: IMPORTED_ACCOUNT_CHILD,
+            },
+        );
+        wipe_extended_private(&mut sub_account_key);
+        key
+    }
+
+    fn imported_cipher_key(
+        &mut self,
+        address_type: AccountAddressType,
+        account: u32,
+        sub_account: u32,
+    ) -> Result<[u8; 32], Error> {
+        let mut account_key = self.imported_account_key(address_type, account, sub_account)?;
+        let mut hasher = Sha256::new();
+        hasher.input(b"imported key");
+        hasher.input(&account_key.private_key.key[..]);
+        wipe_extended_private(&mut account_key);
+        let mut cipher_key = [0u8; 32];
+        hasher.result(&mut cipher_key);
+        hasher.reset();
+        Ok(cipher_key)
+    }
+
+    /// set the hook that audits and might refuse exports of private keys
+    pub fn set_export_audit(&mut self, audit: ExportAudit) {
+        self.export_audit = Some(audit);
+    }
+
+    /// export the private key of a derivation in wallet import format
+    /// only with an audit hook and never in a session
+    pub fn export_wif(
+        &mut self,
+        address_type: AccountAddressType,
+        derivation: &KeyDerivation,
+    ) -> Result<String, Error> {
+        if self.session.is_some() {
+            return Err(Error::Session("private key export in a session"));
+        }
+        if derivation.imported {
+            return Err(Error::Unsupported(
+                "imported keys can not be exported, they are not derived from the seed",
+            ));
+        }
+        let audit = self
+            .export_audit
+            .as_mut()
+            .ok_or(Error::Unsupported("private key export needs an audit hook"))?;
+        audit(address_type, derivation)?;
+        Ok(self.unlock_derivation(address_type, derivation)?.to_wif())
+    }
+
     pub fn context(&self) -> Arc<SecpContext> {
         self.context.clone()
     }
+
+    /// wipe and forget cached keys of accounts
+    /// the unlocker remains usable and re-derives them on demand,
+    /// a locked session however is out of keys for good
+    pub fn lock(&mut self) {
+        for (_, (purpose, by_purpose)) in self.cached.iter_mut() {
+            wipe_extended_private(purpose);
+            for (_, (coin, by_coin_type)) in by_purpose.iter_mut() {
+                wipe_extended_private(coin);
+                for (_, (account, by_account)) in by_coin_type.iter_mut() {
+                    wipe_extended_private(account);
+                    for (_, script) in by_account.iter_mut() {
+                        wipe_extended_private(script);
+                    }
+                }
+            }
+        }
+        self.cached.clear();
+        // a session can not re-derive its keys
+        if let Some(ref mut session) = self.session {
+            for (_, key) in session.keys.iter_mut() {
+                wipe_extended_private(key);
+            }
+            session.keys.clear();
+            for (_, key) in session.path_keys.iter_mut() {
+                wipe_extended_private(key);
+            }
+            session.path_keys.clear();
+        }
+    }
+}
+
+impl Drop for Unlocker {
+    fn drop(&mut self) {
+        self.lock();
+        wipe_extended_private(&mut self.master_private);
+    }
+}
+
+/// overwrite key material of an extended private key
+/// the secret becomes ONE_KEY so that the key stays valid
+/// the key types give no safe access to their bytes, so unlike seeds they can not be wiped
+/// with secure_memset. The fence keeps the stores from being reordered, but a store to a key
+/// that is dropped right after might still be elided as dead.
+fn wipe_extended_private(key: &mut ExtendedPrivKey) {
+    key.private_key.key = secp256k1::key::ONE_KEY;
+    key.chain_code = ChainCode::from(&[0u8; 32][..]);
+    // keep the compiler from eliding the stores
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// BIP44 coin type of a network
+pub fn coin_type(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => 0,
+        Network::Testnet => 1,
+        Network::Regtest => 1,
+    }
 }
 
 /// Key derivation detail information
 /// coordinates of a key as defined in BIP32 and BIP44
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct KeyDerivation {
     /// m / purpose' / coin_type' / account' / sub / kix
     pub account: u32,
@@ -361,10 +941,16 @@ pub struct KeyDerivation {
     pub tweak: Option<Vec<u8>>,
     /// optional number of blocks this can not be spent after confirmation (OP_CSV)
     pub csv: Option<u16>,
+    /// an imported key, kix is its position in the account of imported keys
+    #[serde(default)]
+    pub imported: bool,
+    /// custom derivation path of the account, the key is at path / kix
+    #[serde(default)]
+    pub path: Option<DerivationPath>,
 }
 
 /// Address type an account is using
-#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub enum AccountAddressType {
     /// legacy pay to public key hash (BIP44)
     P2PKH,
@@ -373,10 +959,21 @@ pub enum AccountAddressType {
     /// native segwit pay to public key hash in bech format (BIP84)
     P2WPKH,
     /// native segwit pay to script
-    /// do not use 44, 49 or 84 for this parameter, to avoid confusion with above types
-    /// Only supports scripts that can be spent with following witness:
-    /// <signature> <scriptCode>
+    /// do not use 44, 48, 49, 84 or 86 for this parameter, to avoid confusion with other types
+    /// sign spends with following witness: <signature> <scriptCode>
+    /// sign_with_satisfier builds other witnesses
     P2WSH(u32),
+    /// transitional segwit pay to script in legacy format, the P2WSH script wrapped into P2SH
+    /// the parameter and witness are as for P2WSH
+    /// the parameter is the purpose of the keys as for P2WSH, use one that no P2WSH account has,
+    /// else both derive the same keys and pay the same key to two scripts
+    P2SHWSH(u32),
+    /// native segwit sorted multisig (BIP48 script type 2')
+    P2WSHMultisig,
+    /// transitional segwit sorted multisig in legacy format (BIP48 script type 1')
+    P2SHWSHMultisig,
+    /// taproot key path spend of the tweaked key in bech32m format (BIP86)
+    P2TR,
 }
 
 impl AccountAddressType {
@@ -386,29 +983,83 @@ impl AccountAddressType {
             AccountAddressType::P2SHWPKH => 49,
             AccountAddressType::P2WPKH => 84,
             AccountAddressType::P2WSH(n) => *n,
+            AccountAddressType::P2SHWSH(n) => *n,
+            AccountAddressType::P2WSHMultisig => 48,
+            AccountAddressType::P2SHWSHMultisig => 48,
+            AccountAddressType::P2TR => 86,
         }
     }
 
+    /// 48 is read as native segwit multisig, other numbers as native segwit P2WSH
+    /// the purpose does not tell wrapped from native, P2SHWSH and P2SHWSHMultisig are read as native
     pub fn from_u32(n: u32) -> AccountAddressType {
         match n {
             44 => AccountAddressType::P2PKH,
+            48 => AccountAddressType::P2WSHMultisig,
             49 => AccountAddressType::P2SHWPKH,
             84 => AccountAddressType::P2WPKH,
+            86 => AccountAddressType::P2TR,
             n => AccountAddressType::P2WSH(n),
         }
     }
+
+    /// script type of BIP48 multisig
+    /// m / 48' / coin_type' / account' / script_type' / sub / kix
+    pub fn script_type(&self) -> Option<u32> {
+        match self {
+            AccountAddressType::P2SHWSHMultisig => Some(1),
+            AccountAddressType::P2WSHMultisig => Some(2),
+            _ => None,
+        }
+    }
+
+    pub fn is_multisig(&self) -> bool {
+        self.script_type().is_some()
+    }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Account {
     address_type: AccountAddressType,
     account_number: u32,
     sub_account_number: u32,
+    #[serde(skip, default = "deserialized_context")]
     context: Arc<SecpContext>,
     master_public: ExtendedPubKey,
-    instantiated: Vec<InstantiatedKey>,
+    instantiated: InstantiatedKeys,
     next: u32,
     look_ahead: u32,
     network: Network,
+    /// signatures needed to spend from a multisig account
+    #[serde(default)]
+    threshold: u32,
+    /// cosigner keys of a multisig account at the level of master_public
+    #[serde(default)]
+    cosigners: Vec<ExtendedPubKey>,
+    /// built from an extended public key, can not sign
+    #[serde(default)]
+    watch_only: bool,
+    /// holds individually imported keys instead of derived ones
+    #[serde(default)]
+    imported: bool,
+    /// custom derivation path of master_public instead of m / purpose' / coin_type' / account' / sub
+    #[serde(default)]
+    path: Option<DerivationPath>,
+    /// script of each key of a P2WSH account
+    #[serde(default)]
+    template: Option<ScriptTemplate>,
+    /// payment code of the contact paying to the tweaked keys of a BIP47 account
+    #[serde(default)]
+    payment_code: Option<PaymentCode>,
+}
+
+thread_local! {
+    static DESERIALIZED_CONTEXT: Arc<SecpContext> = Arc::new(SecpContext::new());
+}
+
+/// context of deserialized accounts, built once per thread instead of once per account
+fn deserialized_context() -> Arc<SecpContext> {
+    DESERIALIZED_CONTEXT.with(|context| context.clone())
 }
 
 impl Account {
@@ -429,58 +1080,427 @@ impl Account {
             sub_account_number,
             context,
             master_public: pubic_key,
-            instantiated: Vec::new(),
+            instantiated: InstantiatedKeys::default(),
             next: 0,
             look_ahead,
             network: pubic_key.network,
+            threshold: 0,
+            cosigners: Vec::new(),
+            watch_only: false,
+            imported: false,
+            path: None,
+            template: None,
+            payment_code: None,
         };
         sub.do_look_ahead(None)?;
         Ok(sub)
     }
 
-    pub fn new_from_storage(
+    /// create a watch-only account from the extended public key at m / purpose' / coin_type' / account'
+    /// its keys are derived as usual, but it can not sign
+    pub fn new_watch_only(
+        account_public: &ExtendedPubKey,
         address_type: AccountAddressType,
         account_number: u32,
         sub_account_number: u32,
-        master_public: ExtendedPubKey,
-        instantiated: Vec<InstantiatedKey>,
-        next: u32,
         look_ahead: u32,
-        network: Network,
-    ) -> Account {
-        let context = Arc::new(SecpContext::new());
-        Account {
+    ) -> Result<Account, Error> {
+        if address_type.is_multisig() {
+            return Err(Error::Unsupported(
+                "new_watch_only can not be used for multisig accounts",
+            ));
+        }
+        let pubic_key = SecpContext::new().public_child(
+            account_public,
+            ChildNumber::Normal {
+                index: sub_account_number,
+            },
+        )?;
+        Self::new_watch_only_sub(
+            &pubic_key,
             address_type,
             account_number,
             sub_account_number,
-            context,
-            master_public,
-            instantiated,
-            next,
+            None,
             look_ahead,
-            network,
-        }
-    }
-
-    pub fn address_type(&self) -> AccountAddressType {
-        self.address_type
+        )
     }
 
-    pub fn account_number(&self) -> u32 {
-        self.account_number
+    /// create a watch-only account from the extended public key of its sub account
+    /// at m / purpose' / coin_type' / account' / sub, e.g. of an output descriptor
+    /// P2WSH accounts need a template to look ahead
+    pub fn new_watch_only_sub(
+        sub_account_public: &ExtendedPubKey,
+        address_type: AccountAddressType,
+        account_number: u32,
+        sub_account_number: u32,
+        template: Option<ScriptTemplate>,
+        look_ahead: u32,
+    ) -> Result<Account, Error> {
+        if address_type.is_multisig() {
+            return Err(Error::Unsupported(
+                "watch-only accounts can not be multisig",
+            ));
+        }
+        let mut sub = Account {
+            address_type,
+            account_number,
+            sub_account_number,
+            context: Arc::new(SecpContext::new()),
+            master_public: *sub_account_public,
+            instantiated: InstantiatedKeys::default(),
+            next: 0,
+            look_ahead,
+            network: sub_account_public.network,
+            threshold: 0,
+            cosigners: Vec::new(),
+            watch_only: true,
+            imported: false,
+            path: None,
+            template: None,
+            payment_code: None,
+        };
+        if let Some(template) = template {
+            sub.set_template(template)?;
+        }
+        sub.do_look_ahead(None)?;
+        Ok(sub)
     }
 
-    pub fn sub_account_number(&self) -> u32 {
-        self.sub_account_number
+    /// create an account with keys at a custom derivation path / kix
+    /// for wallets not following m / purpose' / coin_type' / account' / sub, e.g. m/0'/0
+    /// account and sub account numbers only identify the account within its master
+    pub fn new_with_path(
+        unlocker: &mut Unlocker,
+        address_type: AccountAddressType,
+        account_number: u32,
+        sub_account_number: u32,
+        path: DerivationPath,
+        look_ahead: u32,
+    ) -> Result<Account, Error> {
+        if address_type.is_multisig() {
+            return Err(Error::Unsupported(
+                "custom derivation path for multisig accounts",
+            ));
+        }
+        let context = Arc::new(SecpContext::new());
+        let master_private = unlocker.path_key(&path)?;
+        let master_public = context.extended_public_from_private(&master_private);
+        let mut sub = Account {
+            address_type,
+            account_number,
+            sub_account_number,
+            context,
+            master_public,
+            instantiated: InstantiatedKeys::default(),
+            next: 0,
+            look_ahead,
+            network: master_public.network,
+            threshold: 0,
+            cosigners: Vec::new(),
+            watch_only: false,
+            imported: false,
+            path: Some(path),
+            template: None,
+            payment_code: None,
+        };
+        sub.do_look_ahead(None)?;
+        Ok(sub)
     }
 
-    pub fn master_public(&self) -> &ExtendedPubKey {
-        &self.master_public
+    /// create a P2WSH account with the scripts of a template
+    pub fn new_with_template(
+        unlocker: &mut Unlocker,
+        address_type: AccountAddressType,
+        account_number: u32,
+        sub_account_number: u32,
+        template: ScriptTemplate,
+        look_ahead: u32,
+    ) -> Result<Account, Error> {
+        let mut account = Account::new(
+            unlocker,
+            address_type,
+            account_number,
+            sub_account_number,
+            0,
+        )?;
+        account.set_template(template)?;
+        account.look_ahead = look_ahead;
+        account.do_look_ahead(None)?;
+        Ok(account)
     }
 
-    pub fn next(&self) -> u32 {
-        self.next
-    }
+    /// create an account of individually imported keys of P2PKH, P2SHWPKH or P2WPKH type
+    /// private keys are encrypted with a key of the master at
+    /// m / purpose' / coin_type' / account' / sub / 0', not shared with derived keys
+    /// a MasterAccount holds one account per account and sub account number,
+    /// use a number that no account of derived keys has
+    pub fn new_imported(
+        unlocker: &mut Unlocker,
+        address_type: AccountAddressType,
+        account_number: u32,
+        sub_account_number: u32,
+    ) -> Result<Account, Error> {
+        match address_type {
+            AccountAddressType::P2PKH
+            | AccountAddressType::P2SHWPKH
+            | AccountAddressType::P2WPKH => {}
+            _ => {
+                return Err(Error::Unsupported(
+                    "imported keys are of P2PKH, P2SHWPKH or P2WPKH type",
+                ))
+            }
+        }
+        let context = Arc::new(SecpContext::new());
+        let mut master_private =
+            unlocker.imported_account_key(address_type, account_number, sub_account_number)?;
+        let master_public = context.extended_public_from_private(&master_private);
+        wipe_extended_private(&mut master_private);
+        Ok(Account {
+            address_type,
+            account_number,
+            sub_account_number,
+            context,
+            master_public,
+            instantiated: InstantiatedKeys::default(),
+            next: 0,
+            look_ahead: 0,
+            network: master_public.network,
+            threshold: 0,
+            cosigners: Vec::new(),
+            watch_only: false,
+            imported: true,
+            path: None,
+            template: None,
+            payment_code: None,
+        })
+    }
+
+    /// import a private key, returns its kix
+    pub fn add_imported_private(
+        &mut self,
+        unlocker: &mut Unlocker,
+        key: &PrivateKey,
+    ) -> Result<u32, Error> {
+        if !self.imported {
+            return Err(Error::Unsupported("not an account of imported keys"));
+        }
+        let mut account_key = unlocker.imported_account_key(
+            self.address_type,
+            self.account_number,
+            self.sub_account_number,
+        )?;
+        let account_public = self.context.extended_public_from_private(&account_key);
+        wipe_extended_private(&mut account_key);
+        if account_public != self.master_public {
+            return Err(Error::Passphrase);
+        }
+        let public = self.context.public_from_private(key);
+        let encrypted = unlocker.encrypt_imported(
+            self.address_type,
+            self.account_number,
+            self.sub_account_number,
+            key,
+        )?;
+        self.add_imported(public, Some(encrypted))
+    }
+
+    /// import a watch-only public key, returns its kix
+    pub fn add_imported_public(&mut self, key: &PublicKey) -> Result<u32, Error> {
+        if !self.imported {
+            return Err(Error::Unsupported("not an account of imported keys"));
+        }
+        self.add_imported(*key, None)
+    }
+
+    fn add_imported(
+        &mut self,
+        public: PublicKey,
+        encrypted: Option<Vec<u8>>,
+    ) -> Result<u32, Error> {
+        if !public.compressed && self.address_type != AccountAddressType::P2PKH {
+            return Err(Error::Unsupported("segwit needs compressed keys"));
+        }
+        if self
+            .get_key_by_script(
+                &key_address(
+                    self.address_type,
+                    self.network,
+                    &public,
+                    &Script::new(),
+                    &self.context,
+                )?
+                .script_pubkey(),
+            )
+            .is_some()
+        {
+            return Err(Error::Unsupported("key is already imported"));
+        }
+        let kix = self.instantiated.len() as u32;
+        let script_code = single_key_script_code(self.address_type, &public);
+        let address = key_address(
+            self.address_type,
+            self.network,
+            &public,
+            &script_code,
+            &self.context,
+        )?;
+        self.instantiated.push(InstantiatedKey {
+            public,
+            script_code,
+            address,
+            tweak: None,
+            csv: None,
+            encrypted,
+        });
+        Ok(kix)
+    }
+
+    /// add the next key of a P2PKH, P2SHWPKH or P2WPKH account with an additive tweak
+    /// for keys look-ahead can not derive, such as those of BIP47 payment codes
+    pub fn add_tweaked_key(&mut self, tweak: &[u8]) -> Result<u32, Error> {
+        match self.address_type {
+            AccountAddressType::P2PKH
+            | AccountAddressType::P2SHWPKH
+            | AccountAddressType::P2WPKH => {}
+            _ => {
+                return Err(Error::Unsupported(
+                    "add_tweaked_key is for P2PKH, P2SHWPKH or P2WPKH accounts",
+                ))
+            }
+        }
+        if self.imported {
+            return Err(Error::Unsupported("imported keys are not derived"));
+        }
+        let kix = self.instantiated.len() as u32;
+        let address_type = self.address_type;
+        let instantiated = InstantiatedKey::new(
+            address_type,
+            self.network,
+            &self.master_public,
+            Some(tweak),
+            kix,
+            |public: &PublicKey, _| single_key_script_code(address_type, public),
+            None,
+            self.context.clone(),
+        )?;
+        self.instantiated.push(instantiated);
+        Ok(kix)
+    }
+
+    /// create a sorted multisig account (BIP48, BIP67)
+    /// cosigners are their extended public keys at m / 48' / coin_type' / account' / script_type'
+    pub fn new_multisig(
+        unlocker: &mut Unlocker,
+        address_type: AccountAddressType,
+        account_number: u32,
+        sub_account_number: u32,
+        threshold: u32,
+        cosigners: &[ExtendedPubKey],
+        look_ahead: u32,
+    ) -> Result<Account, Error> {
+        if !address_type.is_multisig() {
+            return Err(Error::Unsupported(
+                "new_multisig can only be used for multisig accounts",
+            ));
+        }
+        if threshold < 1 || threshold as usize > cosigners.len() + 1 || cosigners.len() >= 15 {
+            return Err(Error::Unsupported(
+                "multisig needs 1 <= threshold <= keys <= 15",
+            ));
+        }
+        let context = Arc::new(SecpContext::new());
+        let master_private =
+            unlocker.sub_account_key(address_type, account_number, sub_account_number)?;
+        let pubic_key = context.extended_public_from_private(&master_private);
+        let mut cosigner_subs = Vec::new();
+        for cosigner in cosigners {
+            if cosigner.network != pubic_key.network {
+                return Err(Error::Network);
+            }
+            let cosigner_sub = context.public_child(
+                cosigner,
+                ChildNumber::Normal {
+                    index: sub_account_number,
+                },
+            )?;
+            if cosigner_sub.public_key == pubic_key.public_key {
+                return Err(Error::Unsupported("own key can not be a cosigner"));
+            }
+            cosigner_subs.push(cosigner_sub);
+        }
+        let mut sub = Account {
+            address_type,
+            account_number,
+            sub_account_number,
+            context,
+            master_public: pubic_key,
+            instantiated: InstantiatedKeys::default(),
+            next: 0,
+            look_ahead,
+            network: pubic_key.network,
+            threshold,
+            cosigners: cosigner_subs,
+            watch_only: false,
+            imported: false,
+            path: None,
+            template: None,
+            payment_code: None,
+        };
+        sub.do_look_ahead(None)?;
+        Ok(sub)
+    }
+
+    pub fn new_from_storage(
+        address_type: AccountAddressType,
+        account_number: u32,
+        sub_account_number: u32,
+        master_public: ExtendedPubKey,
+        instantiated: Vec<InstantiatedKey>,
+        next: u32,
+        look_ahead: u32,
+        network: Network,
+    ) -> Account {
+        let context = Arc::new(SecpContext::new());
+        Account {
+            address_type,
+            account_number,
+            sub_account_number,
+            context,
+            master_public,
+            instantiated: instantiated.into(),
+            next,
+            look_ahead,
+            network,
+            threshold: 0,
+            cosigners: Vec::new(),
+            watch_only: false,
+            imported: false,
+            path: None,
+            template: None,
+            payment_code: None,
+        }
+    }
+
+    pub fn address_type(&self) -> AccountAddressType {
+        self.address_type
+    }
+
+    pub fn account_number(&self) -> u32 {
+        self.account_number
+    }
+
+    pub fn sub_account_number(&self) -> u32 {
+        self.sub_account_number
+    }
+
+    pub fn master_public(&self) -> &ExtendedPubKey {
+        &self.master_public
+    }
+
+    pub fn next(&self) -> u32 {
+        self.next
+    }
 
     pub fn look_ahead(&self) -> u32 {
         self.look_ahead
@@ -490,8 +1510,59 @@ impl Account {
         self.network
     }
 
+    pub fn is_watch_only(&self) -> bool {
+        self.watch_only
+    }
+
+    pub fn is_imported(&self) -> bool {
+        self.imported
+    }
+
+    /// custom derivation path of the account, if it has one
+    pub fn path(&self) -> Option<&DerivationPath> {
+        self.path.as_ref()
+    }
+
+    /// script template of a P2WSH account, if it has one
+    pub fn template(&self) -> Option<ScriptTemplate> {
+        self.template
+    }
+
+    /// payment code of the contact paying to a BIP47 account, if it is one
+    pub fn payment_code(&self) -> Option<&PaymentCode> {
+        self.payment_code.as_ref()
+    }
+
+    /// bind the payment code of a contact to an account of its tweaked keys
+    pub(crate) fn set_payment_code(&mut self, payment_code: PaymentCode) {
+        self.payment_code = Some(payment_code);
+    }
+
+    /// bind a script template to a P2WSH account, look-ahead and next_key then create its scripts
+    pub fn set_template(&mut self, template: ScriptTemplate) -> Result<(), Error> {
+        match self.address_type {
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => {
+                self.template = Some(template);
+                Ok(())
+            }
+            _ => Err(Error::Unsupported(
+                "script templates are for P2WSH accounts",
+            )),
+        }
+    }
+
     pub fn instantiated(&self) -> &Vec<InstantiatedKey> {
-        &self.instantiated
+        &self.instantiated.keys
+    }
+
+    /// signatures needed to spend from a multisig account
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    /// cosigner keys of a multisig account at the level of master_public
+    pub fn cosigners(&self) -> &Vec<ExtendedPubKey> {
+        &self.cosigners
     }
 
     /// look ahead from last seen
@@ -502,6 +1573,10 @@ impl Account {
             self.next = max(self.next, seen + 1);
         }
 
+        // keys of payment codes are tweaked by the contact, register_payment_code adds them
+        if self.imported || self.payment_code.is_some() {
+            return Ok(Vec::new());
+        }
         let seen = seen.unwrap_or(0);
         let have = self.instantiated.len() as u32;
         let need = max(seen + self.look_ahead, have) - have;
@@ -518,6 +1593,16 @@ impl Account {
     fn instantiate_more(&mut self) -> Result<&InstantiatedKey, Error> {
         let kix = self.instantiated.len() as u32;
 
+        let mut keys = Vec::new();
+        for cosigner in &self.cosigners {
+            keys.push(
+                self.context
+                    .public_child(cosigner, ChildNumber::Normal { index: kix })?
+                    .public_key,
+            );
+        }
+        let threshold = self.threshold;
+        let template = self.template;
         let scripter = |public: &PublicKey, _| match self.address_type {
             AccountAddressType::P2SHWPKH => Builder::new()
                 .push_opcode(all::OP_DUP)
@@ -533,6 +1618,14 @@ impl Account {
                 .push_opcode(all::OP_EQUALVERIFY)
                 .push_opcode(all::OP_CHECKSIG)
                 .into_script(),
+            AccountAddressType::P2WSHMultisig | AccountAddressType::P2SHWSHMultisig => {
+                keys.push(*public);
+                sorted_multisig_script(threshold, keys.as_slice())
+            }
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => match template {
+                Some(template) => template.script(public),
+                None => Script::new(),
+            },
             _ => Script::new(),
         };
         let instantiated = InstantiatedKey::new(
@@ -542,7 +1635,7 @@ impl Account {
             None,
             kix,
             scripter,
-            None,
+            template.and_then(|t| t.csv()),
             self.context.clone(),
         )?;
 
@@ -554,13 +1647,25 @@ impl Account {
     /// create a new key
     pub fn next_key(&mut self) -> Result<&InstantiatedKey, Error> {
         match self.address_type {
-            AccountAddressType::P2WSH(_) => {
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_)
+                if self.template.is_none() =>
+            {
                 return Err(Error::Unsupported(
-                    "next_key can not be used for P2WSH accounts",
+                    "next_key can not be used for P2WSH accounts without template",
                 ))
             }
             _ => {}
         }
+        if self.imported {
+            return Err(Error::Unsupported(
+                "next_key can not be used for accounts of imported keys",
+            ));
+        }
+        if self.payment_code.is_some() {
+            return Err(Error::Unsupported(
+                "next_key can not be used for accounts of payment codes",
+            ));
+        }
         self.instantiate_more()?;
         let key = &self.instantiated[self.next as usize];
         self.next += 1;
@@ -574,11 +1679,44 @@ impl Account {
             .public_key)
     }
 
+    /// BIP32 path of a key of this account from the master key
+    /// m / purpose' / coin_type' / account' / sub / kix
+    /// or for multisig m / 48' / coin_type' / account' / script_type' / sub / kix
+    pub fn derivation_path(&self, kix: u32) -> DerivationPath {
+        if let Some(ref path) = self.path {
+            return path.child(ChildNumber::Normal { index: kix });
+        }
+        let mut path = vec![
+            ChildNumber::Hardened {
+                index: self.address_type.as_u32(),
+            },
+            ChildNumber::Hardened {
+                index: coin_type(self.network),
+            },
+            ChildNumber::Hardened {
+                index: self.account_number,
+            },
+        ];
+        if let Some(script_type) = self.address_type.script_type() {
+            path.push(ChildNumber::Hardened { index: script_type });
+        }
+        path.push(ChildNumber::Normal {
+            index: self.sub_account_number,
+        });
+        path.push(ChildNumber::Normal { index: kix });
+        DerivationPath::from(path)
+    }
+
     /// get a previously instantiated key
     pub fn get_key(&self, kix: u32) -> Option<&InstantiatedKey> {
         self.instantiated.get(kix as usize)
     }
 
+    /// get a previously instantiated key and its kix by its pubkey script
+    pub fn get_key_by_script(&self, script_pubkey: &Script) -> Option<(u32, &InstantiatedKey)> {
+        self.instantiated.by_script(script_pubkey)
+    }
+
     pub fn add_script_key<W>(
         &mut self,
         scripter: W,
@@ -589,7 +1727,7 @@ impl Account {
         W: FnOnce(&PublicKey, Option<u16>) -> Script,
     {
         match self.address_type {
-            AccountAddressType::P2WSH(_) => {}
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => {}
             _ => {
                 return Err(Error::Unsupported(
                     "add_script_key can only be used for P2WSH accounts",
@@ -629,35 +1767,96 @@ impl Account {
         })
     }
 
-    /// sign a transaction with keys in this account works for types except P2WSH
-    pub fn sign<R>(
+    /// sign a transaction with keys in this account
+    /// P2WSH scripts are spent with the witness <signature> <scriptCode>
+    pub fn sign<R, S>(
         &self,
         transaction: &mut Transaction,
         hash_type: SigHashType,
         resolver: R,
-        unlocker: &mut Unlocker,
+        signer: &mut S,
+    ) -> Result<usize, Error>
+    where
+        R: Fn(&OutPoint) -> Option<TxOut>,
+        S: Signer + ?Sized,
+    {
+        self.sign_with_satisfier(transaction, hash_type, resolver, signer, &SingleSignature)
+    }
+
+    /// sign a transaction with keys in this account
+    /// witnesses of P2WSH scripts are built of the elements the satisfier supplies
+    /// signatures of other keys are only made for keys of this account,
+    /// use MasterAccount::sign_with_satisfier for keys of other accounts
+    pub fn sign_with_satisfier<R, S, W>(
+        &self,
+        transaction: &mut Transaction,
+        hash_type: SigHashType,
+        resolver: R,
+        signer: &mut S,
+        satisfier: &W,
+    ) -> Result<usize, Error>
+    where
+        R: Fn(&OutPoint) -> Option<TxOut>,
+        S: Signer + ?Sized,
+        W: Satisfier + ?Sized,
+    {
+        self.sign_with_keys(
+            transaction,
+            hash_type,
+            resolver,
+            signer,
+            satisfier,
+            &|address_type: AccountAddressType, derivation: &KeyDerivation| {
+                if (derivation.account, derivation.sub)
+                    != (self.account_number, self.sub_account_number)
+                {
+                    return None;
+                }
+                self.public_key(address_type, derivation)
+            },
+        )
+    }
+
+    /// public key of a derivation of this account
+    fn public_key(
+        &self,
+        address_type: AccountAddressType,
+        derivation: &KeyDerivation,
+    ) -> Option<PublicKey> {
+        if address_type != self.address_type
+            || derivation.imported != self.imported
+            || derivation.path != self.path
+        {
+            return None;
+        }
+        self.get_key(derivation.kix).map(|k| k.public)
+    }
+
+    /// sign as sign_with_satisfier, signatures of other keys are checked against keys
+    fn sign_with_keys<R, S, W, K>(
+        &self,
+        transaction: &mut Transaction,
+        hash_type: SigHashType,
+        resolver: R,
+        signer: &mut S,
+        satisfier: &W,
+        keys: &K,
     ) -> Result<usize, Error>
     where
         R: Fn(&OutPoint) -> Option<TxOut>,
+        S: Signer + ?Sized,
+        W: Satisfier + ?Sized,
+        K: Fn(AccountAddressType, &KeyDerivation) -> Option<PublicKey>,
     {
         let mut signed = 0;
         let txclone = transaction.clone();
         let mut bip143hasher: Option<bip143::SighashComponents> = None;
         for (ix, input) in transaction.input.iter_mut().enumerate() {
             if let Some(spend) = resolver(&input.previous_output) {
-                if let Some((kix, instantiated)) = self
-                    .instantiated
-                    .iter()
-                    .enumerate()
-                    .find(|(_, i)| i.address.script_pubkey() == spend.script_pubkey)
-                {
-                    let pk = unlocker.unlock(
-                        self.address_type,
-                        self.account_number,
-                        self.sub_account_number,
-                        kix as u32,
-                        instantiated.tweak.clone(),
-                    )?;
+                if let Some((kix, instantiated)) = self.get_key_by_script(&spend.script_pubkey) {
+                    if self.watch_only && signer.derives_from_seed() {
+                        return Err(Error::WatchOnly);
+                    }
                     match self.address_type {
                         AccountAddressType::P2PKH => {
                             let sighash = txclone.signature_hash(
@@ -665,8 +1864,8 @@ impl Account {
                                 &instantiated.address.script_pubkey(),
                                 hash_type.as_u32(),
                             );
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            let mut with_hashtype = signature.to_vec();
+                            let mut with_hashtype =
+                                self.signature(signer, &sighash[..], kix, instantiated, &spend)?;
                             with_hashtype.push(hash_type.as_u32() as u8);
                             input.script_sig = Builder::new()
                                 .push_slice(with_hashtype.as_slice())
@@ -676,20 +1875,20 @@ impl Account {
                             signed += 1;
                         }
                         AccountAddressType::P2WPKH => {
-                            if hash_type.as_u32() & SigHashType::All.as_u32() == 0 {
-                                return Err(Error::Unsupported("can only sig all inputs for now"));
-                            }
                             input.script_sig = Script::new();
                             let hasher =
                                 bip143hasher.unwrap_or(bip143::SighashComponents::new(&txclone));
-                            let sighash = hasher.sighash_all(
-                                &txclone.input[ix],
+                            let sighash = bip143_sighash(
+                                &hasher,
+                                &txclone,
+                                ix,
                                 &instantiated.script_code,
                                 spend.value,
+                                hash_type,
                             );
                             bip143hasher = Some(hasher);
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            let mut with_hashtype = signature.to_vec();
+                            let mut with_hashtype =
+                                self.signature(signer, &sighash[..], kix, instantiated, &spend)?;
                             with_hashtype.push(hash_type.as_u32() as u8);
                             input.witness.clear();
                             input.witness.push(with_hashtype);
@@ -697,9 +1896,6 @@ impl Account {
                             signed += 1;
                         }
                         AccountAddressType::P2SHWPKH => {
-                            if hash_type.as_u32() & SigHashType::All.as_u32() == 0 {
-                                return Err(Error::Unsupported("can only sig all inputs for now"));
-                            }
                             input.script_sig = Builder::new()
                                 .push_slice(
                                     &Builder::new()
@@ -714,39 +1910,204 @@ impl Account {
                                 .into_script();
                             let hasher =
                                 bip143hasher.unwrap_or(bip143::SighashComponents::new(&txclone));
-                            let sighash = hasher.sighash_all(
-                                &txclone.input[ix],
+                            let sighash = bip143_sighash(
+                                &hasher,
+                                &txclone,
+                                ix,
                                 &instantiated.script_code,
                                 spend.value,
+                                hash_type,
                             );
                             bip143hasher = Some(hasher);
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            let mut with_hashtype = signature.to_vec();
+                            let mut with_hashtype =
+                                self.signature(signer, &sighash[..], kix, instantiated, &spend)?;
                             with_hashtype.push(hash_type.as_u32() as u8);
                             input.witness.clear();
                             input.witness.push(with_hashtype);
                             input.witness.push(instantiated.public.to_bytes());
                             signed += 1;
                         }
-                        AccountAddressType::P2WSH(_) => {
-                            if hash_type.as_u32() & SigHashType::All.as_u32() == 0 {
-                                return Err(Error::Unsupported("can only sig all inputs for now"));
+                        AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => {
+                            input.script_sig = match self.address_type {
+                                AccountAddressType::P2SHWSH(_) => Builder::new()
+                                    .push_slice(&instantiated.script_code.to_v0_p2wsh()[..])
+                                    .into_script(),
+                                _ => Script::new(),
+                            };
+                            let hasher =
+                                bip143hasher.unwrap_or(bip143::SighashComponents::new(&txclone));
+                            let sighash = bip143_sighash(
+                                &hasher,
+                                &txclone,
+                                ix,
+                                &instantiated.script_code,
+                                spend.value,
+                                hash_type,
+                            );
+                            bip143hasher = Some(hasher);
+                            let elements = satisfier
+                                .satisfy(
+                                    &instantiated.script_code,
+                                    &self.key_derivation(kix, instantiated),
+                                )
+                                .unwrap_or_else(|| vec![WitnessElement::Signature]);
+                            input.witness.clear();
+                            for element in elements {
+                                let mut with_hashtype = match element {
+                                    WitnessElement::Signature => self.signature(
+                                        signer,
+                                        &sighash[..],
+                                        kix,
+                                        instantiated,
+                                        &spend,
+                                    )?,
+                                    WitnessElement::KeySignature(address_type, derivation) => {
+                                        if derivation.imported {
+                                            return Err(Error::Unsupported(
+                                                "imported keys sign only for their own scripts",
+                                            ));
+                                        }
+                                        let public = keys(address_type, &derivation).ok_or(
+                                            Error::Unsupported("key of a signature is not known"),
+                                        )?;
+                                        let signature = signer.sign(
+                                            &sighash[..],
+                                            &derivation,
+                                            address_type,
+                                            &spend,
+                                        )?;
+                                        // as of own keys, signatures of signers are checked
+                                        self.context.verify(
+                                            &sighash[..],
+                                            &Signature::from_der(signature.as_slice())?,
+                                            &public,
+                                        )?;
+                                        signature
+                                    }
+                                    WitnessElement::Push(data) => {
+                                        input.witness.push(data);
+                                        continue;
+                                    }
+                                    WitnessElement::Branch(branch) => {
+                                        input.witness.push(if branch {
+                                            vec![1u8]
+                                        } else {
+                                            Vec::new()
+                                        });
+                                        continue;
+                                    }
+                                };
+                                with_hashtype.push(hash_type.as_u32() as u8);
+                                input.witness.push(with_hashtype);
+                                signed += 1;
                             }
-                            input.script_sig = Script::new();
+                            input.witness.push(instantiated.script_code.to_bytes());
+                        }
+                        AccountAddressType::P2WSHMultisig | AccountAddressType::P2SHWSHMultisig => {
+                            input.script_sig = match self.address_type {
+                                AccountAddressType::P2SHWSHMultisig => Builder::new()
+                                    .push_slice(&instantiated.script_code.to_v0_p2wsh()[..])
+                                    .into_script(),
+                                _ => Script::new(),
+                            };
                             let hasher =
                                 bip143hasher.unwrap_or(bip143::SighashComponents::new(&txclone));
-                            let sighash = hasher.sighash_all(
-                                &txclone.input[ix],
+                            let sighash = bip143_sighash(
+                                &hasher,
+                                &txclone,
+                                ix,
                                 &instantiated.script_code,
                                 spend.value,
+                                hash_type,
                             );
+                            let (threshold, keys) = parse_multisig_script(
+                                &instantiated.script_code,
+                            )
+                            .ok_or(Error::Unsupported("multisig account with other script"))?;
+                            // signatures of cosigners by position of their key
+                            // each might have been made with an other hash type
+                            let mut signatures = HashMap::new();
+                            if input.witness.last() == Some(&instantiated.script_code.to_bytes()) {
+                                for sig in input.witness[..input.witness.len() - 1].iter() {
+                                    if sig.is_empty() {
+                                        continue;
+                                    }
+                                    let their_hash_type =
+                                        SigHashType::from_u32(u32::from(sig[sig.len() - 1]));
+                                    if their_hash_type.as_u32() != u32::from(sig[sig.len() - 1]) {
+                                        continue;
+                                    }
+                                    let their_sighash = bip143_sighash(
+                                        &hasher,
+                                        &txclone,
+                                        ix,
+                                        &instantiated.script_code,
+                                        spend.value,
+                                        their_hash_type,
+                                    );
+                                    let signature = Signature::from_der(&sig[..sig.len() - 1])?;
+                                    if let Some(pos) = keys.iter().position(|k| {
+                                        self.context
+                                            .verify(&their_sighash[..], &signature, k)
+                                            .is_ok()
+                                    }) {
+                                        signatures.insert(pos, sig.clone());
+                                    }
+                                }
+                            }
                             bip143hasher = Some(hasher);
-                            let signature = self.context.sign(&sighash[..], &pk)?.serialize_der();
-                            let mut with_hashtype = signature.to_vec();
-                            with_hashtype.push(hash_type.as_u32() as u8);
+                            let own = keys
+                                .iter()
+                                .position(|k| *k == instantiated.public)
+                                .ok_or(Error::Unsupported("own key not in multisig script"))?;
+                            if signatures.len() < threshold || signatures.contains_key(&own) {
+                                let mut with_hashtype = self.signature(
+                                    signer,
+                                    &sighash[..],
+                                    kix,
+                                    instantiated,
+                                    &spend,
+                                )?;
+                                with_hashtype.push(hash_type.as_u32() as u8);
+                                signatures.insert(own, with_hashtype);
+                                signed += 1;
+                            }
+                            let mut positions = signatures.keys().cloned().collect::<Vec<_>>();
+                            positions.sort();
                             input.witness.clear();
-                            input.witness.push(with_hashtype);
+                            // dummy consumed by OP_CHECKMULTISIG
+                            input.witness.push(Vec::new());
+                            for pos in positions {
+                                input.witness.push(signatures.remove(&pos).unwrap());
+                            }
                             input.witness.push(instantiated.script_code.to_bytes());
+                        }
+                        AccountAddressType::P2TR => {
+                            // BIP341 commits to the outputs spent by all inputs
+                            let spent = txclone
+                                .input
+                                .iter()
+                                .map(|i| resolver(&i.previous_output))
+                                .collect::<Option<Vec<_>>>()
+                                .ok_or(Error::Unsupported(
+                                    "taproot signing needs outputs spent by all inputs",
+                                ))?;
+                            // SIGHASH_DEFAULT commits to the same as SIGHASH_ALL but saves a byte
+                            let taproot_hash_type = if hash_type == SigHashType::All {
+                                0u8
+                            } else {
+                                hash_type.as_u32() as u8
+                            };
+                            let sighash =
+                                taproot::sighash(&txclone, ix, &spent, taproot_hash_type)?;
+                            let mut signature =
+                                self.signature(signer, &sighash[..], kix, instantiated, &spend)?;
+                            if taproot_hash_type != 0 {
+                                signature.push(taproot_hash_type);
+                            }
+                            input.script_sig = Script::new();
+                            input.witness.clear();
+                            input.witness.push(signature);
                             signed += 1;
                         }
                     }
@@ -755,21 +2116,172 @@ impl Account {
         }
         Ok(signed)
     }
-}
-
-/// instantiated key of an account
-#[derive(Clone, Serialize, Deserialize)]
-pub struct InstantiatedKey {
-    pub public: PublicKey,
-    pub script_code: Script,
-    pub address: Address,
-    pub tweak: Option<Vec<u8>>,
-    pub csv: Option<u16>,
-}
 
-impl InstantiatedKey {
-    pub fn new<W>(
-        address_type: AccountAddressType,
+    /// signature of a key of this account by the signer, checked against the key
+    pub fn signature<S>(
+        &self,
+        signer: &mut S,
+        sighash: &[u8],
+        kix: u32,
+        instantiated: &InstantiatedKey,
+        spend: &TxOut,
+    ) -> Result<Vec<u8>, Error>
+    where
+        S: Signer + ?Sized,
+    {
+        let derivation = self.key_derivation(kix, instantiated);
+        let signature = if self.imported {
+            let encrypted = instantiated.encrypted.as_ref().ok_or(Error::WatchOnly)?;
+            signer.sign_imported(
+                sighash,
+                &derivation,
+                self.address_type,
+                &instantiated.public,
+                encrypted.as_slice(),
+            )?
+        } else {
+            signer.sign(sighash, &derivation, self.address_type, spend)?
+        };
+        match self.address_type {
+            AccountAddressType::P2TR => self.context.schnorr_verify(
+                sighash,
+                signature.as_slice(),
+                &self.context.taproot_output_key(&instantiated.public)?,
+            )?,
+            _ => self.context.verify(
+                sighash,
+                &Signature::from_der(signature.as_slice())?,
+                &instantiated.public,
+            )?,
+        }
+        Ok(signature)
+    }
+
+    fn key_derivation(&self, kix: u32, instantiated: &InstantiatedKey) -> KeyDerivation {
+        KeyDerivation {
+            account: self.account_number,
+            sub: self.sub_account_number,
+            kix,
+            tweak: instantiated.tweak.clone(),
+            csv: instantiated.csv,
+            imported: self.imported,
+            path: self.path.clone(),
+        }
+    }
+}
+
+/// BIP143 signature hash of a segwit input for any hash type
+/// components are computed once for all inputs of the transaction
+pub fn bip143_sighash(
+    components: &bip143::SighashComponents,
+    transaction: &Transaction,
+    input_index: usize,
+    script_code: &Script,
+    value: u64,
+    hash_type: SigHashType,
+) -> sha256d::Hash {
+    let anyone_can_pay = hash_type.as_u32() & 0x80 != 0;
+    let base = hash_type.as_u32() & 0x1f;
+    let hash_prevouts = if anyone_can_pay {
+        sha256d::Hash::default()
+    } else {
+        components.hash_prevouts
+    };
+    let hash_sequence = if anyone_can_pay || base != SigHashType::All.as_u32() {
+        sha256d::Hash::default()
+    } else {
+        components.hash_sequence
+    };
+    let hash_outputs = if base == SigHashType::All.as_u32() {
+        components.hash_outputs
+    } else if base == SigHashType::Single.as_u32() && input_index < transaction.output.len() {
+        sha256d::Hash::hash(&serialize(&transaction.output[input_index]))
+    } else {
+        sha256d::Hash::default()
+    };
+    let txin = &transaction.input[input_index];
+    let mut data = Vec::new();
+    data.extend(serialize(&transaction.version));
+    data.extend(&hash_prevouts[..]);
+    data.extend(&hash_sequence[..]);
+    data.extend(serialize(&txin.previous_output));
+    data.extend(serialize(script_code));
+    data.extend(serialize(&value));
+    data.extend(serialize(&txin.sequence));
+    data.extend(&hash_outputs[..]);
+    data.extend(serialize(&transaction.lock_time));
+    data.extend(serialize(&hash_type.as_u32()));
+    sha256d::Hash::hash(&data)
+}
+
+/// sorted multisig script (BIP67)
+/// OP_threshold <keys in lexicographic order> OP_n OP_CHECKMULTISIG
+pub fn sorted_multisig_script(threshold: u32, keys: &[PublicKey]) -> Script {
+    let mut keys = keys.iter().map(|k| k.to_bytes()).collect::<Vec<_>>();
+    keys.sort();
+    keys.iter()
+        .fold(Builder::new().push_int(threshold as i64), |b, k| {
+            b.push_slice(k.as_slice())
+        })
+        .push_int(keys.len() as i64)
+        .push_opcode(all::OP_CHECKMULTISIG)
+        .into_script()
+}
+
+/// threshold and keys of a multisig script
+pub fn parse_multisig_script(script: &Script) -> Option<(usize, Vec<PublicKey>)> {
+    let pushnum = |op: opcodes::All| {
+        let code = op.into_u8();
+        if code >= all::OP_PUSHNUM_1.into_u8() && code <= all::OP_PUSHNUM_16.into_u8() {
+            Some((code - all::OP_PUSHNUM_1.into_u8() + 1) as usize)
+        } else {
+            None
+        }
+    };
+    let instructions = script.iter(true).collect::<Vec<_>>();
+    if instructions.len() < 4 {
+        return None;
+    }
+    let threshold = match instructions[0] {
+        Instruction::Op(op) => pushnum(op)?,
+        _ => return None,
+    };
+    let mut keys = Vec::new();
+    for i in &instructions[1..instructions.len() - 2] {
+        match i {
+            Instruction::PushBytes(k) => keys.push(PublicKey::from_slice(k).ok()?),
+            _ => return None,
+        }
+    }
+    match (
+        &instructions[instructions.len() - 2],
+        &instructions[instructions.len() - 1],
+    ) {
+        (Instruction::Op(n), Instruction::Op(all::OP_CHECKMULTISIG))
+            if pushnum(*n) == Some(keys.len()) && threshold <= keys.len() =>
+        {
+            Some((threshold, keys))
+        }
+        _ => None,
+    }
+}
+
+/// instantiated key of an account
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InstantiatedKey {
+    pub public: PublicKey,
+    pub script_code: Script,
+    pub address: Address,
+    pub tweak: Option<Vec<u8>>,
+    pub csv: Option<u16>,
+    /// encrypted private key of an imported key
+    #[serde(default)]
+    pub encrypted: Option<Vec<u8>>,
+}
+
+impl InstantiatedKey {
+    pub fn new<W>(
+        address_type: AccountAddressType,
         network: Network,
         master: &ExtendedPubKey,
         tweak: Option<&[u8]>,
@@ -788,30 +2300,271 @@ impl InstantiatedKey {
             context.tweak_exp_add(&mut public, tweak)?;
         }
         let script_code = scripter(&public, csv);
-        let address = match address_type {
-            AccountAddressType::P2PKH => Address::p2pkh(&public, network),
-            AccountAddressType::P2SHWPKH => Address::p2shwpkh(&public, network),
-            AccountAddressType::P2WPKH => Address::p2wpkh(&public, network),
-            AccountAddressType::P2WSH(_) => Address::p2wsh(&script_code, network),
-        };
+        let address = key_address(address_type, network, &public, &script_code, &context)?;
         Ok(InstantiatedKey {
             public,
             script_code,
             address,
             tweak: tweak.map(|t| t.to_vec()),
             csv,
+            encrypted: None,
         })
     }
+
+    /// the address as to be shown, bech32m for P2TR
+    /// the address field displays segwit version 1 with the bech32 checksum
+    pub fn address_string(&self) -> String {
+        taproot::encode_address(&self.address)
+    }
+}
+
+/// script code of P2SHWPKH and P2WPKH keys as signed by BIP143, none for P2PKH
+fn single_key_script_code(address_type: AccountAddressType, public: &PublicKey) -> Script {
+    match address_type {
+        AccountAddressType::P2PKH => Script::new(),
+        _ => Builder::new()
+            .push_opcode(all::OP_DUP)
+            .push_opcode(all::OP_HASH160)
+            .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
+            .push_opcode(all::OP_EQUALVERIFY)
+            .push_opcode(all::OP_CHECKSIG)
+            .into_script(),
+    }
+}
+
+fn key_address(
+    address_type: AccountAddressType,
+    network: Network,
+    public: &PublicKey,
+    script_code: &Script,
+    context: &SecpContext,
+) -> Result<Address, Error> {
+    Ok(match address_type {
+        AccountAddressType::P2PKH => Address::p2pkh(public, network),
+        AccountAddressType::P2SHWPKH => Address::p2shwpkh(public, network),
+        AccountAddressType::P2WPKH => Address::p2wpkh(public, network),
+        AccountAddressType::P2WSH(_) => Address::p2wsh(script_code, network),
+        AccountAddressType::P2SHWSH(_) => Address::p2shwsh(script_code, network),
+        AccountAddressType::P2WSHMultisig => Address::p2wsh(script_code, network),
+        AccountAddressType::P2SHWSHMultisig => Address::p2shwsh(script_code, network),
+        AccountAddressType::P2TR => taproot::address(&context.taproot_output_key(public)?, network),
+    })
+}
+
+/// instantiated keys of an account indexed by their pubkey script
+/// stored as the list of keys, the index is rebuilt on load
+#[derive(Default)]
+struct InstantiatedKeys {
+    keys: Vec<InstantiatedKey>,
+    index: HashMap<Script, u32>,
+}
+
+impl InstantiatedKeys {
+    fn push(&mut self, key: InstantiatedKey) {
+        // the first key of a script is found, as by a search
+        self.index
+            .entry(key.address.script_pubkey())
+            .or_insert(self.keys.len() as u32);
+        self.keys.push(key);
+    }
+
+    fn by_script(&self, script_pubkey: &Script) -> Option<(u32, &InstantiatedKey)> {
+        self.index
+            .get(script_pubkey)
+            .map(|kix| (*kix, &self.keys[*kix as usize]))
+    }
+}
+
+impl Deref for InstantiatedKeys {
+    type Target = Vec<InstantiatedKey>;
+
+    fn deref(&self) -> &Vec<InstantiatedKey> {
+        &self.keys
+    }
+}
+
+impl From<Vec<InstantiatedKey>> for InstantiatedKeys {
+    fn from(keys: Vec<InstantiatedKey>) -> InstantiatedKeys {
+        let mut instantiated = InstantiatedKeys::default();
+        for key in keys {
+            instantiated.push(key);
+        }
+        instantiated
+    }
 }
 
+impl Serialize for InstantiatedKeys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.keys.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InstantiatedKeys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<InstantiatedKeys, D::Error> {
+        Ok(Vec::<InstantiatedKey>::deserialize(deserializer)?.into())
+    }
+}
+
+/// length of the authentication tag of an encrypted imported key
+const IMPORTED_KEY_TAG: usize = 16;
+/// hardened child of the sub account key that accounts of imported keys are at
+const IMPORTED_ACCOUNT_CHILD: u32 = 0;
+
 /// seed of the master key
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Seed(pub Vec<u8>);
 
+impl Drop for Seed {
+    fn drop(&mut self) {
+        secure_memset(self.0.as_mut_slice(), 0);
+    }
+}
+
+/// first bytes of a versioned encrypted seed
+const ENCRYPTED_SEED_MAGIC: [u8; 4] = *b"rwsd";
+/// scrypt with AES256-GCM
+pub const ENCRYPTED_SEED_VERSION: u8 = 1;
+/// scrypt cost parameters of new encryptions: N = 2^15, r = 8, p = 1 (32 MiB)
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+/// bounds of scrypt cost parameters read from a stored header
+/// memory of scrypt is 128 * r * N bytes, at most 1 GiB
+const SCRYPT_MAX_LOG_N: u8 = 20;
+const SCRYPT_MAX_R: u32 = 32;
+const SCRYPT_MAX_P: u32 = 16;
+const SCRYPT_MAX_MEMORY: u64 = 1 << 30;
+/// magic, version, log_n, r, p, salt, nonce
+const ENCRYPTED_SEED_HEADER: usize = 4 + 1 + 1 + 4 + 4 + 16 + 12;
+const ENCRYPTED_SEED_TAG: usize = 16;
+/// legacy encryptions are padded to blocks of AES, encryptions of the versioned format are not
+const LEGACY_BLOCK: usize = 16;
+
 impl Seed {
     /// encrypt seed
-    /// encryption algorithm: AES256(Sha256(passphrase), ECB, PKCS padding
+    /// encryption algorithm: AES256-GCM with key scrypt(passphrase, random salt)
+    /// format: magic | version | log_n | r | p | salt | nonce | ciphertext | tag
+    /// the header is authenticated as additional data
     pub fn encrypt(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
+        self.encrypt_with_cost(passphrase, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)
+    }
+
+    /// encrypt seed with explicit scrypt cost parameters
+    pub fn encrypt_with_cost(
+        &self,
+        passphrase: &str,
+        log_n: u8,
+        r: u32,
+        p: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let len = ENCRYPTED_SEED_HEADER + self.0.len() + ENCRYPTED_SEED_TAG;
+        // usize::is_multiple_of needs Rust 1.87
+        #[allow(clippy::manual_is_multiple_of)]
+        if len % LEGACY_BLOCK == 0 {
+            return Err(Error::Unsupported(
+                "seed length that can not be told from the legacy format",
+            ));
+        }
+        let mut encrypted = Vec::with_capacity(len);
+        encrypted.extend_from_slice(&ENCRYPTED_SEED_MAGIC);
+        encrypted.push(ENCRYPTED_SEED_VERSION);
+        encrypted.push(log_n);
+        encrypted.extend_from_slice(&r.to_be_bytes());
+        encrypted.extend_from_slice(&p.to_be_bytes());
+        let mut salt_and_nonce = [0u8; 16 + 12];
+        thread_rng().fill_bytes(&mut salt_and_nonce);
+        encrypted.extend_from_slice(&salt_and_nonce);
+
+        let mut key = Self::derive_key(passphrase, &encrypted)?;
+        let mut cipher = AesGcm::new(
+            aes::KeySize::KeySize256,
+            &key,
+            &encrypted[ENCRYPTED_SEED_HEADER - 12..ENCRYPTED_SEED_HEADER],
+            &encrypted[..ENCRYPTED_SEED_HEADER],
+        );
+        let mut ciphertext = vec![0u8; self.0.len()];
+        let mut tag = [0u8; ENCRYPTED_SEED_TAG];
+        cipher.encrypt(self.0.as_slice(), ciphertext.as_mut_slice(), &mut tag);
+        secure_memset(&mut key, 0);
+        encrypted.extend(ciphertext);
+        encrypted.extend_from_slice(&tag);
+        Ok(encrypted)
+    }
+
+    /// decrypt seed
+    /// accepts the versioned format of encrypt and the legacy AES256(Sha256(passphrase), ECB)
+    /// a wrong passphrase is only detected for the versioned format
+    pub fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<Seed, Error> {
+        if Self::is_legacy(encrypted) {
+            return Self::decrypt_legacy(encrypted, passphrase);
+        }
+        if encrypted[4] != ENCRYPTED_SEED_VERSION {
+            return Err(Error::Unsupported("unknown seed encryption version"));
+        }
+        let mut key = Self::derive_key(passphrase, encrypted)?;
+        let mut cipher = AesGcm::new(
+            aes::KeySize::KeySize256,
+            &key,
+            &encrypted[ENCRYPTED_SEED_HEADER - 12..ENCRYPTED_SEED_HEADER],
+            &encrypted[..ENCRYPTED_SEED_HEADER],
+        );
+        let ciphertext = &encrypted[ENCRYPTED_SEED_HEADER..encrypted.len() - ENCRYPTED_SEED_TAG];
+        // wiped on drop
+        let mut decrypted = Seed(vec![0u8; ciphertext.len()]);
+        let authentic = cipher.decrypt(
+            ciphertext,
+            decrypted.0.as_mut_slice(),
+            &encrypted[encrypted.len() - ENCRYPTED_SEED_TAG..],
+        );
+        secure_memset(&mut key, 0);
+        if !authentic {
+            return Err(Error::Passphrase);
+        }
+        Ok(decrypted)
+    }
+
+    /// true if encrypted with the legacy unversioned format
+    /// a legacy encryption might start with the magic by chance, but its length is of whole blocks
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn is_legacy(encrypted: &[u8]) -> bool {
+        encrypted.len() < ENCRYPTED_SEED_HEADER + ENCRYPTED_SEED_TAG
+            || encrypted.len() % LEGACY_BLOCK == 0
+            || encrypted[..4] != ENCRYPTED_SEED_MAGIC
+    }
+
+    /// scrypt key with cost parameters and salt of a header
+    fn derive_key(passphrase: &str, header: &[u8]) -> Result<[u8; 32], Error> {
+        let log_n = header[5];
+        let mut r = [0u8; 4];
+        r.copy_from_slice(&header[6..10]);
+        let mut p = [0u8; 4];
+        p.copy_from_slice(&header[10..14]);
+        let (r, p) = (u32::from_be_bytes(r), u32::from_be_bytes(p));
+        // the parameter check of scrypt would panic, the header might be tampered with
+        if log_n == 0
+            || log_n > SCRYPT_MAX_LOG_N
+            || r == 0
+            || r > SCRYPT_MAX_R
+            || p == 0
+            || p > SCRYPT_MAX_P
+            || log_n as usize >= r as usize * 16
+            || (128 * u64::from(r)) << log_n > SCRYPT_MAX_MEMORY
+        {
+            return Err(Error::Unsupported("invalid scrypt parameters"));
+        }
+        let mut key = [0u8; 32];
+        scrypt::scrypt(
+            passphrase.as_bytes(),
+            &header[14..30],
+            &scrypt::ScryptParams::new(log_n, r, p),
+            &mut key,
+        );
+        Ok(key)
+    }
+
+    /// legacy encryption: AES256(Sha256(passphrase), ECB, PKCS padding
+    #[cfg(test)]
+    fn encrypt_legacy(&self, passphrase: &str) -> Result<Vec<u8>, Error> {
         let mut key = [0u8; 32];
         let mut sha2 = Sha256::new();
         sha2.input(passphrase.as_bytes());
@@ -840,23 +2593,30 @@ impl Seed {
         Ok(encrypted)
     }
 
-    /// decrypt seed
-    /// decryption algorithm: AES256(Sha256(passphrase), ECB, PKCS padding
-    pub fn decrypt(encrypted: &[u8], passphrase: &str) -> Result<Seed, Error> {
+    /// legacy decryption: AES256(Sha256(passphrase), ECB, PKCS padding
+    fn decrypt_legacy(encrypted: &[u8], passphrase: &str) -> Result<Seed, Error> {
         let mut key = [0u8; 32];
         let mut sha2 = Sha256::new();
         sha2.input(passphrase.as_bytes());
         sha2.result(&mut key);
 
-        let mut decrypted = Vec::new();
+        // reserved not to leave copies behind on growth, wiped on drop
+        let mut decrypted = Seed(Vec::with_capacity(encrypted.len()));
         let mut reader = buffer::RefReadBuffer::new(encrypted);
         let mut buffer = [0u8; 1024];
         let mut writer = buffer::RefWriteBuffer::new(&mut buffer);
         let mut decryptor =
             aes::ecb_decryptor(aes::KeySize::KeySize256, &key, blockmodes::PkcsPadding {});
+        let mut failed = None;
         loop {
-            let result = decryptor.decrypt(&mut reader, &mut writer, true)?;
-            decrypted.extend(
+            let result = match decryptor.decrypt(&mut reader, &mut writer, true) {
+                Ok(result) => result,
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            };
+            decrypted.0.extend(
                 writer
                     .take_read_buffer()
                     .take_remaining()
@@ -868,8 +2628,12 @@ impl Seed {
                 BufferResult::BufferOverflow => {}
             }
         }
-
-        Ok(Seed(decrypted))
+        secure_memset(&mut buffer, 0);
+        secure_memset(&mut key, 0);
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+        Ok(decrypted)
     }
 }
 
@@ -879,6 +2643,7 @@ mod test {
     use std::fs::File;
     use std::io::Read;
     use std::path::PathBuf;
+    use std::str::FromStr;
 
     use bitcoin::blockdata::opcodes::all;
     use bitcoin::blockdata::script::Builder;
@@ -892,18 +2657,744 @@ mod test {
 
     use super::*;
 
-    const PASSPHRASE: &str = "correct horse battery staple";
-    const RBF: u32 = 0xffffffff - 2;
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const RBF: u32 = 0xffffffff - 2;
+
+    #[test]
+    fn seed_encrypt_decrypt() {
+        let mut secret = [0u8; 32];
+        thread_rng().fill(&mut secret);
+        let seed = Seed(secret.to_vec());
+        assert_eq!(
+            Seed::decrypt(seed.encrypt("whatever").unwrap().as_slice(), "whatever").unwrap(),
+            seed
+        );
+        let mut encrypted = seed.encrypt("whatever").unwrap();
+        assert!(!Seed::is_legacy(encrypted.as_slice()));
+        match Seed::decrypt(encrypted.as_slice(), "wrong") {
+            Err(Error::Passphrase) => {}
+            _ => panic!("wrong passphrase not detected"),
+        }
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(Seed::decrypt(encrypted.as_slice(), "whatever").is_err());
+        // cost parameters are authenticated
+        let mut encrypted = seed.encrypt_with_cost("whatever", 10, 8, 1).unwrap();
+        encrypted[5] = 11;
+        assert!(Seed::decrypt(encrypted.as_slice(), "whatever").is_err());
+        // tampered cost parameters are rejected before running scrypt
+        for (log_n, r, p) in [
+            (57, 8, 1),
+            (40, 8, 1),
+            (21, 1, 1),
+            (20, 16, 1),
+            (10, 1 << 31, 1),
+            (10, 8, 1 << 30),
+            (0, 8, 1),
+        ]
+        .iter()
+        {
+            let mut tampered = encrypted.clone();
+            tampered[5] = *log_n;
+            tampered[6..10].copy_from_slice(&u32::to_be_bytes(*r));
+            tampered[10..14].copy_from_slice(&u32::to_be_bytes(*p));
+            assert!(Seed::decrypt(tampered.as_slice(), "whatever").is_err());
+        }
+        assert!(seed.encrypt_with_cost("whatever", 24, 8, 1).is_err());
+        // the versioned format is never of whole AES blocks
+        assert!(Seed(vec![0x42; 22]).encrypt("whatever").is_err());
+
+        let mut legacy = seed.encrypt_legacy("whatever").unwrap();
+        assert!(Seed::is_legacy(legacy.as_slice()));
+        assert_eq!(Seed::decrypt(legacy.as_slice(), "whatever").unwrap(), seed);
+        // legacy ciphertext starting with the magic by chance
+        legacy[..4].copy_from_slice(&ENCRYPTED_SEED_MAGIC);
+        legacy[4] = ENCRYPTED_SEED_VERSION;
+        assert!(Seed::is_legacy(legacy.as_slice()));
+    }
+
+    #[test]
+    fn change_passphrase() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let account = Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap();
+        master.add_account(account);
+        let seed = master.seed(Network::Bitcoin, PASSPHRASE).unwrap();
+        let encrypted = master.encrypted().clone();
+        let birth = master.birth();
+        let master_public = *master.master_public();
+
+        assert!(master.change_passphrase("wrong", "new").is_err());
+        assert_eq!(master.encrypted(), &encrypted);
+
+        master.change_passphrase(PASSPHRASE, "new").unwrap();
+        assert!(Unlocker::new_for_master(&master, PASSPHRASE).is_err());
+        Unlocker::new_for_master(&master, "new").unwrap();
+        assert_eq!(master.seed(Network::Bitcoin, "new").unwrap(), seed);
+        assert_eq!(master.accounts().len(), 1);
+        assert_eq!(master.birth(), birth);
+        assert_eq!(master.master_public(), &master_public);
+    }
+
+    #[test]
+    fn unlocker_lock() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let key = unlocker
+            .unlock(AccountAddressType::P2WPKH, 0, 0, 1, None)
+            .unwrap();
+        assert_eq!(unlocker.cached.len(), 1);
+        unlocker.lock();
+        assert!(unlocker.cached.is_empty());
+        assert_eq!(
+            unlocker
+                .unlock(AccountAddressType::P2WPKH, 0, 0, 1, None)
+                .unwrap(),
+            key
+        );
+
+        let mut extended = *unlocker.master_private();
+        wipe_extended_private(&mut extended);
+        assert_ne!(extended, *unlocker.master_private());
+        assert_eq!(extended.private_key.key, secp256k1::key::ONE_KEY);
+        assert_eq!(&extended.chain_code[..], &[0u8; 32][..]);
+
+        // a locked session is out of keys, also of custom paths
+        let path = DerivationPath::from_str("m/0'/0").unwrap();
+        let scope = UnlockerScope {
+            accounts: vec![(AccountAddressType::P2WPKH, 0, 0)],
+            paths: vec![path.clone()],
+            ..Default::default()
+        };
+        let mut session = Unlocker::new_session(&master, PASSPHRASE, &scope).unwrap();
+        assert_eq!(
+            session.path_key(&path).unwrap(),
+            unlocker.path_key(&path).unwrap()
+        );
+        session.lock();
+        assert!(session.path_key(&path).is_err());
+        assert!(session
+            .unlock(AccountAddressType::P2WPKH, 0, 0, 1, None)
+            .is_err());
+    }
+
+    #[test]
+    fn unlocker_session() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let key = unlocker
+            .unlock(AccountAddressType::P2WPKH, 0, 1, 3, None)
+            .unwrap();
+
+        let scope = UnlockerScope {
+            accounts: vec![(AccountAddressType::P2WPKH, 0, 1)],
+            expires_after: None,
+            max_signatures: Some(2),
+            paths: Vec::new(),
+        };
+        let mut session = Unlocker::new_session(&master, PASSPHRASE, &scope).unwrap();
+        assert!(session.is_session());
+        assert!(session.try_master_private().is_err());
+        assert!(session
+            .unlock(AccountAddressType::P2WPKH, 0, 0, 3, None)
+            .is_err());
+        assert!(session
+            .unlock(AccountAddressType::P2SHWPKH, 0, 1, 3, None)
+            .is_err());
+        assert!(Account::new(&mut session, AccountAddressType::P2PKH, 0, 1, 10).is_err());
+        let account = Account::new(&mut session, AccountAddressType::P2WPKH, 0, 1, 10).unwrap();
+        assert_eq!(
+            account.get_key(3).unwrap().public,
+            unlocker.context().public_from_private(&key)
+        );
+        assert_eq!(
+            session
+                .unlock(AccountAddressType::P2WPKH, 0, 1, 3, None)
+                .unwrap(),
+            key
+        );
+        session
+            .unlock(AccountAddressType::P2WPKH, 0, 1, 4, None)
+            .unwrap();
+        // only signatures count against the limit
+        let derivation = KeyDerivation {
+            account: 0,
+            sub: 1,
+            kix: 3,
+            tweak: None,
+            csv: None,
+            imported: false,
+            path: None,
+        };
+        let spent = TxOut {
+            value: 0,
+            script_pubkey: account.get_key(3).unwrap().address.script_pubkey(),
+        };
+        for _ in 0..2 {
+            session
+                .sign(&[1u8; 32], &derivation, AccountAddressType::P2WPKH, &spent)
+                .unwrap();
+        }
+        // limit of signatures reached
+        assert!(session
+            .sign(&[1u8; 32], &derivation, AccountAddressType::P2WPKH, &spent)
+            .is_err());
+        assert!(session
+            .unlock(AccountAddressType::P2WPKH, 0, 1, 3, None)
+            .is_err());
+
+        let scope = UnlockerScope {
+            accounts: vec![(AccountAddressType::P2WPKH, 0, 1)],
+            expires_after: Some(Duration::from_secs(0)),
+            max_signatures: None,
+            paths: Vec::new(),
+        };
+        let mut session = Unlocker::new_session(&master, PASSPHRASE, &scope).unwrap();
+        assert!(session
+            .unlock(AccountAddressType::P2WPKH, 0, 1, 3, None)
+            .is_err());
+    }
+
+    #[test]
+    fn export_wif() {
+        use std::sync::{Arc, Mutex};
+
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
+        );
+        let derivation = KeyDerivation {
+            account: 0,
+            sub: 0,
+            kix: 3,
+            tweak: None,
+            csv: None,
+            imported: false,
+            path: None,
+        };
+        // refused without audit
+        assert!(unlocker
+            .export_wif(AccountAddressType::P2WPKH, &derivation)
+            .is_err());
+
+        let audited = Arc::new(Mutex::new(Vec::new()));
+        let log = audited.clone();
+        unlocker.set_export_audit(Box::new(move |address_type, derivation| {
+            if derivation.sub != 0 {
+                return Err(Error::Unsupported("only receiving keys"));
+            }
+            log.lock().unwrap().push((address_type, derivation.clone()));
+            Ok(())
+        }));
+        let wif = unlocker
+            .export_wif(AccountAddressType::P2WPKH, &derivation)
+            .unwrap();
+        let key = PrivateKey::from_wif(&wif).unwrap();
+        assert_eq!(
+            SecpContext::new().public_from_private(&key),
+            master.get((0, 0)).unwrap().get_key(3).unwrap().public
+        );
+        assert_eq!(
+            *audited.lock().unwrap(),
+            vec![(AccountAddressType::P2WPKH, derivation.clone())]
+        );
+        let change = KeyDerivation {
+            sub: 1,
+            ..derivation.clone()
+        };
+        assert!(unlocker
+            .export_wif(AccountAddressType::P2WPKH, &change)
+            .is_err());
+        assert_eq!(audited.lock().unwrap().len(), 1);
+
+        // never in a session
+        let scope = UnlockerScope {
+            accounts: vec![(AccountAddressType::P2WPKH, 0, 0)],
+            ..Default::default()
+        };
+        let mut session = Unlocker::new_session(&master, PASSPHRASE, &scope).unwrap();
+        session.set_export_audit(Box::new(|_, _| Ok(())));
+        assert!(session
+            .export_wif(AccountAddressType::P2WPKH, &derivation)
+            .is_err());
+    }
+
+    #[test]
+    fn upgrade_legacy_encryption() {
+        let mut secret = [0u8; 32];
+        thread_rng().fill(&mut secret);
+        let seed = Seed(secret.to_vec());
+        let master = MasterAccount::from_seed(&seed, 0, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut legacy = MasterAccount::from_encrypted(
+            seed.encrypt_legacy(PASSPHRASE).unwrap().as_slice(),
+            *master.master_public(),
+            0,
+        );
+        assert!(legacy.unlock("wrong").is_err());
+        assert!(Seed::is_legacy(legacy.encrypted().as_slice()));
+        legacy.unlock(PASSPHRASE).unwrap();
+        assert!(!Seed::is_legacy(legacy.encrypted().as_slice()));
+        assert_eq!(legacy.seed(Network::Bitcoin, PASSPHRASE).unwrap(), seed);
+
+        // unlocking without the master does not upgrade
+        let mut legacy = MasterAccount::from_encrypted(
+            seed.encrypt_legacy(PASSPHRASE).unwrap().as_slice(),
+            *master.master_public(),
+            0,
+        );
+        Unlocker::new_for_master(&legacy, PASSPHRASE).unwrap();
+        assert!(Seed::is_legacy(legacy.encrypted().as_slice()));
+        assert!(legacy.upgrade_encryption("wrong").is_err());
+        assert!(legacy.upgrade_encryption(PASSPHRASE).unwrap());
+        assert!(!Seed::is_legacy(legacy.encrypted().as_slice()));
+        assert!(!legacy.upgrade_encryption(PASSPHRASE).unwrap());
+        assert_eq!(legacy.seed(Network::Bitcoin, PASSPHRASE).unwrap(), seed);
+    }
+
+    #[test]
+    fn master_script_index() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master
+            .add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 0).unwrap());
+        // P2WSH look-ahead without template has the same empty script for all keys
+        master.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 1, 0, 3).unwrap(),
+        );
+        let empty = Script::new().to_v0_p2wsh();
+        assert_eq!(master.get_derivation(&empty).unwrap().kix, 0);
+        assert_eq!(master.get_derivation(&empty).unwrap().account, 1);
+
+        // a key added through get_mut is found before and after it is indexed
+        let script = master
+            .get_mut((0, 0))
+            .unwrap()
+            .next_key()
+            .unwrap()
+            .address
+            .script_pubkey();
+        assert_eq!(master.get_derivation(&script).unwrap().account, 0);
+        master.get_mut((1, 0)).unwrap();
+        assert_eq!(master.scripts.get(&script), Some(&(0, 0)));
+        assert_eq!(master.get_derivation(&script).unwrap().account, 0);
+
+        // look-ahead through the master is indexed
+        let new = master.do_look_ahead((0, 0), Some(3)).unwrap();
+        assert_eq!(new.len(), 2);
+        for (kix, script) in new {
+            assert_eq!(master.scripts.get(&script), Some(&(0, 0)));
+            assert_eq!(master.get_derivation(&script).unwrap().kix, kix);
+        }
+
+        master.remove_account((0, 0));
+        assert_eq!(master.get_derivation(&script), None);
+        assert!(master.scripts.values().all(|k| *k == (1, 0)));
+    }
+
+    #[test]
+    fn master_serde() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master
+            .add_account(Account::new(&mut unlocker, AccountAddressType::P2PKH, 0, 0, 10).unwrap());
+        master.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 1, 0, 0).unwrap(),
+        );
+        master.get_mut((0, 0)).unwrap().next_key().unwrap();
+        master
+            .get_mut((1, 0))
+            .unwrap()
+            .add_script_key(
+                |pk: &PublicKey, _| {
+                    Builder::new()
+                        .push_slice(pk.to_bytes().as_slice())
+                        .push_opcode(all::OP_CHECKSIG)
+                        .into_script()
+                },
+                Some(&[0x01; 32]),
+                Some(10),
+            )
+            .unwrap();
+
+        let stored = serde_json::to_string(&master).unwrap();
+        let restored: MasterAccount = serde_json::from_str(&stored).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), stored);
+        assert_eq!(restored.master_public(), master.master_public());
+        assert_eq!(restored.encrypted(), master.encrypted());
+        assert_eq!(restored.birth(), master.birth());
+        assert_eq!(restored.accounts().len(), 2);
+        assert!(Arc::ptr_eq(
+            &restored.get((0, 0)).unwrap().context,
+            &restored.get((1, 0)).unwrap().context
+        ));
+        for (k, a) in master.accounts() {
+            let r = restored.get(*k).unwrap();
+            assert_eq!(r.address_type(), a.address_type());
+            assert_eq!(r.master_public(), a.master_public());
+            assert_eq!(r.next(), a.next());
+            assert_eq!(r.look_ahead(), a.look_ahead());
+            assert_eq!(
+                r.get_scripts().collect::<Vec<_>>(),
+                a.get_scripts().collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(restored.get((0, 0)).unwrap().next(), 1);
+        // the script index is rebuilt on load
+        for (script, derivation) in master.get_scripts() {
+            assert_eq!(restored.get_derivation(&script), Some(derivation));
+        }
+        assert_eq!(restored.get_derivation(&Script::new()), None);
+        Unlocker::new_for_master(&restored, PASSPHRASE).unwrap();
+
+        let unknown = stored.replacen(
+            &format!("\"version\":{}", STORAGE_VERSION),
+            "\"version\":0",
+            1,
+        );
+        assert!(serde_json::from_str::<MasterAccount>(&unknown).is_err());
+    }
+
+    #[test]
+    fn watch_only() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
+        );
+        let context = SecpContext::new();
+        let account_private = [84, 1, 0].iter().fold(*unlocker.master_private(), |k, i| {
+            context
+                .private_child(&k, ChildNumber::Hardened { index: *i })
+                .unwrap()
+        });
+        let account_public = context.extended_public_from_private(&account_private);
+
+        let mut watching = MasterAccount::watch_only(*master.master_public(), master.birth());
+        watching.add_account(
+            Account::new_watch_only(&account_public, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
+        );
+        let account = watching.get((0, 0)).unwrap();
+        assert!(account.is_watch_only());
+        assert_eq!(account.instantiated().len(), 10);
+        assert_eq!(
+            account.get_scripts().collect::<Vec<_>>(),
+            master
+                .get((0, 0))
+                .unwrap()
+                .get_scripts()
+                .collect::<Vec<_>>()
+        );
+        assert!(Account::new_watch_only(
+            &account_public,
+            AccountAddressType::P2WSHMultisig,
+            0,
+            0,
+            10
+        )
+        .is_err());
+
+        let spent = TxOut {
+            script_pubkey: account.get_key(0).unwrap().address.script_pubkey(),
+            value: 1000,
+        };
+        let mut transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![spent.clone()],
+            lock_time: 0,
+            version: 2,
+        };
+        match watching.sign(
+            &mut transaction,
+            SigHashType::All,
+            &(|_| Some(spent.clone())),
+            &mut unlocker,
+        ) {
+            Err(Error::WatchOnly) => {}
+            _ => panic!("watch-only account signed"),
+        }
+        let stored = serde_json::to_string(&watching).unwrap();
+        let restored: MasterAccount = serde_json::from_str(&stored).unwrap();
+        assert!(restored.get((0, 0)).unwrap().is_watch_only());
+    }
+
+    #[test]
+    fn imported_keys() {
+        use coins::Coins;
+
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let context = SecpContext::new();
+        let mut account =
+            Account::new_imported(&mut unlocker, AccountAddressType::P2WPKH, 1, 0).unwrap();
+        assert!(account.is_imported());
+        assert!(account.next_key().is_err());
+        // apart from derived keys of the same number
+        assert_ne!(
+            account.master_public(),
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 1, 0, 0)
+                .unwrap()
+                .master_public()
+        );
+        let private = PrivateKey {
+            compressed: true,
+            network: Network::Testnet,
+            key: secp256k1::key::SecretKey::from_slice(&[7u8; 32]).unwrap(),
+        };
+        assert_eq!(
+            account
+                .add_imported_private(&mut unlocker, &private)
+                .unwrap(),
+            0
+        );
+        assert!(account
+            .add_imported_private(&mut unlocker, &private)
+            .is_err());
+        let watched = context.public_from_private(&PrivateKey {
+            key: secp256k1::key::SecretKey::from_slice(&[9u8; 32]).unwrap(),
+            ..private
+        });
+        assert_eq!(account.add_imported_public(&watched).unwrap(), 1);
+        assert!(account
+            .add_imported_public(&PublicKey {
+                compressed: false,
+                ..watched
+            })
+            .is_err());
+        master.add_account(account);
+
+        let imported = Address::p2wpkh(&context.public_from_private(&private), Network::Testnet);
+        let funding = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![
+                TxOut {
+                    script_pubkey: imported.script_pubkey(),
+                    value: 100000,
+                },
+                TxOut {
+                    script_pubkey: Address::p2wpkh(&watched, Network::Testnet).script_pubkey(),
+                    value: 100000,
+                },
+            ],
+            lock_time: 0,
+            version: 2,
+        };
+        let mut coins = Coins::new();
+        assert!(coins.process_unconfirmed_transaction(&mut master, &funding));
+        assert_eq!(coins.unconfirmed_balance(), 200000);
+        let coin = &coins.unconfirmed()[&OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        }];
+        assert!(coin.derivation.imported);
+        assert_eq!((coin.derivation.account, coin.derivation.kix), (1, 0));
+        unlocker.set_export_audit(Box::new(|_, _| Ok(())));
+        assert!(unlocker
+            .export_wif(AccountAddressType::P2WPKH, &coin.derivation)
+            .is_err());
+
+        let mut spending = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: funding.txid(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: imported.script_pubkey(),
+                value: 90000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        assert_eq!(
+            master
+                .sign(
+                    &mut spending,
+                    SigHashType::All,
+                    &(|p: &OutPoint| funding.output.get(p.vout as usize).cloned()),
+                    &mut unlocker
+                )
+                .unwrap(),
+            1
+        );
+        spending
+            .verify(|point| funding.output.get(point.vout as usize).cloned())
+            .unwrap();
+
+        // the watched key can not sign
+        spending.input[0].previous_output.vout = 1;
+        spending.input[0].witness.clear();
+        match master.sign(
+            &mut spending,
+            SigHashType::All,
+            &(|p: &OutPoint| funding.output.get(p.vout as usize).cloned()),
+            &mut unlocker,
+        ) {
+            Err(Error::WatchOnly) => {}
+            _ => panic!("watched key signed"),
+        }
+
+        let stored = serde_json::to_string(&master).unwrap();
+        let restored: MasterAccount = serde_json::from_str(&stored).unwrap();
+        let account = restored.get((1, 0)).unwrap();
+        assert!(account.is_imported());
+        assert_eq!(
+            account
+                .get_key_by_script(&imported.script_pubkey())
+                .unwrap()
+                .0,
+            0
+        );
+        assert!(account.get_key(0).unwrap().encrypted.is_some());
+        assert!(account.get_key(1).unwrap().encrypted.is_none());
+    }
+
+    #[test]
+    fn custom_path() {
+        use coins::Coins;
+        use signer::MockSigner;
+
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let context = SecpContext::new();
+        let path = DerivationPath::from_str("m/0'/0").unwrap();
+        let account = Account::new_with_path(
+            &mut unlocker,
+            AccountAddressType::P2PKH,
+            0,
+            0,
+            path.clone(),
+            10,
+        )
+        .unwrap();
+        assert_eq!(account.path(), Some(&path));
+        assert_eq!(
+            account.derivation_path(3),
+            DerivationPath::from_str("m/0'/0/3").unwrap()
+        );
+        let expected = [
+            ChildNumber::Hardened { index: 0 },
+            ChildNumber::Normal { index: 0 },
+            ChildNumber::Normal { index: 3 },
+        ]
+        .iter()
+        .fold(*unlocker.master_private(), |k, c| {
+            context.private_child(&k, *c).unwrap()
+        });
+        assert_eq!(
+            account.get_key(3).unwrap().public,
+            context.public_from_private(&expected.private_key)
+        );
+        assert!(Account::new_with_path(
+            &mut unlocker,
+            AccountAddressType::P2WSHMultisig,
+            1,
+            0,
+            path.clone(),
+            10
+        )
+        .is_err());
+        master.add_account(account);
+
+        let funding = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: master
+                    .get((0, 0))
+                    .unwrap()
+                    .get_key(3)
+                    .unwrap()
+                    .address
+                    .script_pubkey(),
+                value: 100000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let mut coins = Coins::new();
+        assert!(coins.process_unconfirmed_transaction(&mut master, &funding));
+        let derivation = &coins.unconfirmed()[&OutPoint {
+            txid: funding.txid(),
+            vout: 0,
+        }]
+            .derivation;
+        assert_eq!(derivation.path, Some(path.clone()));
+        assert_eq!(derivation.kix, 3);
+
+        let spending = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: funding.txid(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: funding.output.clone(),
+            lock_time: 0,
+            version: 2,
+        };
+        let resolver = |_: &OutPoint| Some(funding.output[0].clone());
+        let mut device = MockSigner::new(*unlocker.master_private());
+        let scope = UnlockerScope {
+            paths: vec![path.clone()],
+            ..Default::default()
+        };
+        let mut session = Unlocker::new_session(&master, PASSPHRASE, &scope).unwrap();
+        for signer in [&mut unlocker as &mut dyn Signer, &mut device, &mut session].iter_mut() {
+            let mut signed = spending.clone();
+            assert_eq!(
+                master
+                    .sign(&mut signed, SigHashType::All, &resolver, *signer)
+                    .unwrap(),
+                1
+            );
+            signed
+                .verify(|point| funding.output.get(point.vout as usize).cloned())
+                .unwrap();
+        }
 
-    #[test]
-    fn seed_encrypt_decrypt() {
-        let mut secret = [0u8; 32];
-        thread_rng().fill(&mut secret);
-        let seed = Seed(secret.to_vec());
-        assert_eq!(
-            Seed::decrypt(seed.encrypt("whatever").unwrap().as_slice(), "whatever").unwrap(),
-            seed
-        );
+        let stored = serde_json::to_string(&master).unwrap();
+        let restored: MasterAccount = serde_json::from_str(&stored).unwrap();
+        assert_eq!(restored.get((0, 0)).unwrap().path(), Some(&path));
     }
 
     #[test]
@@ -1199,6 +3690,97 @@ mod test {
             .unwrap();
     }
 
+    #[test]
+    fn test_shwsh() {
+        use coins::Coins;
+
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let account =
+            Account::new(&mut unlocker, AccountAddressType::P2SHWSH(4711), 0, 0, 0).unwrap();
+        master.add_account(account);
+
+        let script_code = {
+            let account = master.get_mut((0, 0)).unwrap();
+            let scripter = |pk: &PublicKey, _| {
+                Builder::new()
+                    .push_slice(pk.to_bytes().as_slice())
+                    .push_opcode(all::OP_CHECKSIG)
+                    .into_script()
+            };
+            account.add_script_key(scripter, None, None).unwrap();
+            account.get_key(0).unwrap().script_code.clone()
+        };
+
+        let source = master
+            .get((0, 0))
+            .unwrap()
+            .get_key(0)
+            .unwrap()
+            .address
+            .clone();
+        assert_eq!(source.script_pubkey(), script_code.to_v0_p2wsh().to_p2sh());
+        let input_transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: source.script_pubkey(),
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let txid = input_transaction.txid();
+        let mut coins = Coins::new();
+        assert!(coins.process_unconfirmed_transaction(&mut master, &input_transaction));
+        assert_eq!(coins.unconfirmed_balance(), 5000000000);
+
+        let mut spending_transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint { txid, vout: 0 },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: source.script_pubkey(),
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+
+        assert_eq!(
+            master
+                .sign(
+                    &mut spending_transaction,
+                    SigHashType::All,
+                    &(|_| Some(input_transaction.output[0].clone())),
+                    &mut unlocker
+                )
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            spending_transaction.input[0].script_sig,
+            Builder::new()
+                .push_slice(&script_code.to_v0_p2wsh()[..])
+                .into_script()
+        );
+
+        spending_transaction
+            .verify(|point| input_transaction.output.get(point.vout as usize).cloned())
+            .unwrap();
+    }
+
     const CSV: u16 = 10;
 
     #[test]
@@ -1320,6 +3902,404 @@ mod test {
             .is_err());
     }
 
+    #[test]
+    fn test_sighash_types() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        for (n, address_type) in [
+            AccountAddressType::P2PKH,
+            AccountAddressType::P2WPKH,
+            AccountAddressType::P2SHWPKH,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let account = Account::new(&mut unlocker, *address_type, n as u32, 0, 10).unwrap();
+            master.add_account(account);
+        }
+
+        for hash_type in [
+            SigHashType::All,
+            SigHashType::None,
+            SigHashType::Single,
+            SigHashType::AllPlusAnyoneCanPay,
+            SigHashType::NonePlusAnyoneCanPay,
+            SigHashType::SinglePlusAnyoneCanPay,
+        ]
+        .iter()
+        {
+            for n in 1..3 {
+                let source = master
+                    .get((n, 0))
+                    .unwrap()
+                    .get_key(0)
+                    .unwrap()
+                    .address
+                    .clone();
+                let other = master
+                    .get((0, 0))
+                    .unwrap()
+                    .get_key(1)
+                    .unwrap()
+                    .address
+                    .clone();
+                let input_transaction = Transaction {
+                    input: vec![TxIn {
+                        previous_output: OutPoint {
+                            txid: sha256d::Hash::default(),
+                            vout: 0,
+                        },
+                        sequence: RBF,
+                        witness: Vec::new(),
+                        script_sig: Script::new(),
+                    }],
+                    output: vec![
+                        TxOut {
+                            script_pubkey: source.script_pubkey(),
+                            value: 5000000000,
+                        },
+                        TxOut {
+                            script_pubkey: other.script_pubkey(),
+                            value: 5000000000,
+                        },
+                    ],
+                    lock_time: 0,
+                    version: 2,
+                };
+                let txid = input_transaction.txid();
+
+                let mut spending_transaction = Transaction {
+                    input: vec![
+                        TxIn {
+                            previous_output: OutPoint { txid, vout: 0 },
+                            sequence: RBF,
+                            witness: Vec::new(),
+                            script_sig: Script::new(),
+                        },
+                        TxIn {
+                            previous_output: OutPoint { txid, vout: 1 },
+                            sequence: RBF,
+                            witness: Vec::new(),
+                            script_sig: Script::new(),
+                        },
+                    ],
+                    output: vec![
+                        TxOut {
+                            script_pubkey: other.script_pubkey(),
+                            value: 5000000000,
+                        },
+                        TxOut {
+                            script_pubkey: source.script_pubkey(),
+                            value: 4000000000,
+                        },
+                    ],
+                    lock_time: 0,
+                    version: 2,
+                };
+
+                assert_eq!(
+                    master
+                        .sign(
+                            &mut spending_transaction,
+                            *hash_type,
+                            &(|point: &OutPoint| input_transaction
+                                .output
+                                .get(point.vout as usize)
+                                .cloned()),
+                            &mut unlocker
+                        )
+                        .unwrap(),
+                    2
+                );
+                for input in &spending_transaction.input {
+                    let sig = if input.witness.is_empty() {
+                        match input.script_sig.iter(true).next() {
+                            Some(Instruction::PushBytes(sig)) => sig.to_vec(),
+                            _ => panic!("no signature"),
+                        }
+                    } else {
+                        input.witness[0].clone()
+                    };
+                    assert_eq!(sig[sig.len() - 1] as u32, hash_type.as_u32());
+                }
+
+                spending_transaction
+                    .verify(|point| input_transaction.output.get(point.vout as usize).cloned())
+                    .unwrap();
+            }
+        }
+    }
+
+    fn multisig_account_key(unlocker: &Unlocker, script_type: u32) -> ExtendedPubKey {
+        let context = unlocker.context();
+        let key = [48, 0, 0, script_type]
+            .iter()
+            .fold(*unlocker.master_private(), |k, i| {
+                context
+                    .private_child(&k, ChildNumber::Hardened { index: *i })
+                    .unwrap()
+            });
+        context.extended_public_from_private(&key)
+    }
+
+    fn test_multisig(address_type: AccountAddressType) {
+        let script_type = address_type.script_type().unwrap();
+        let mut masters = Vec::new();
+        let mut unlockers = Vec::new();
+        for _ in 0..3 {
+            let master =
+                MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE)
+                    .unwrap();
+            unlockers.push(Unlocker::new_for_master(&master, PASSPHRASE).unwrap());
+            masters.push(master);
+        }
+        let account_keys = unlockers
+            .iter()
+            .map(|u| multisig_account_key(u, script_type))
+            .collect::<Vec<_>>();
+        for (i, (master, unlocker)) in masters.iter_mut().zip(unlockers.iter_mut()).enumerate() {
+            let cosigners = account_keys
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, k)| *k)
+                .collect::<Vec<_>>();
+            let account =
+                Account::new_multisig(unlocker, address_type, 0, 0, 2, &cosigners, 10).unwrap();
+            master.add_account(account);
+        }
+        // own key is not a cosigner
+        assert!(
+            Account::new_multisig(&mut unlockers[0], address_type, 0, 0, 2, &account_keys, 10)
+                .is_err()
+        );
+        // all cosigners see the same addresses
+        let source = masters[0]
+            .get((0, 0))
+            .unwrap()
+            .get_key(5)
+            .unwrap()
+            .address
+            .clone();
+        for master in &masters[1..] {
+            assert_eq!(
+                master.get((0, 0)).unwrap().get_key(5).unwrap().address,
+                source
+            );
+        }
+        assert_eq!(
+            masters[0]
+                .get((0, 0))
+                .unwrap()
+                .derivation_path(5)
+                .to_string(),
+            format!("m/48'/0'/0'/{}'/0/5", script_type)
+        );
+
+        let input_transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: source.script_pubkey(),
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let txid = input_transaction.txid();
+
+        let mut spending_transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint { txid, vout: 0 },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: source.script_pubkey(),
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+
+        let mut spent = HashMap::new();
+        spent.insert(input_transaction.txid(), input_transaction.clone());
+        let verify = |tx: &Transaction| {
+            tx.verify(|point| {
+                spent
+                    .get(&point.txid)
+                    .and_then(|t| t.output.get(point.vout as usize).cloned())
+            })
+        };
+
+        // the third cosigner signs first with an other hash type,
+        // a single signature is not sufficient
+        assert_eq!(
+            masters[2]
+                .sign(
+                    &mut spending_transaction,
+                    SigHashType::AllPlusAnyoneCanPay,
+                    &(|_| Some(input_transaction.output[0].clone())),
+                    &mut unlockers[2]
+                )
+                .unwrap(),
+            1
+        );
+        assert!(verify(&spending_transaction).is_err());
+        // signing again does not add a duplicate
+        masters[2]
+            .sign(
+                &mut spending_transaction,
+                SigHashType::AllPlusAnyoneCanPay,
+                &(|_| Some(input_transaction.output[0].clone())),
+                &mut unlockers[2],
+            )
+            .unwrap();
+        assert_eq!(spending_transaction.input[0].witness.len(), 3);
+
+        // the first cosigner adds the second signature next to the existing one
+        assert_eq!(
+            masters[0]
+                .sign(
+                    &mut spending_transaction,
+                    SigHashType::All,
+                    &(|_| Some(input_transaction.output[0].clone())),
+                    &mut unlockers[0]
+                )
+                .unwrap(),
+            1
+        );
+        assert_eq!(spending_transaction.input[0].witness.len(), 4);
+        verify(&spending_transaction).unwrap();
+    }
+
+    #[test]
+    fn test_wsh_multisig() {
+        test_multisig(AccountAddressType::P2WSHMultisig);
+    }
+
+    #[test]
+    fn test_shwsh_multisig() {
+        test_multisig(AccountAddressType::P2SHWSHMultisig);
+    }
+
+    #[test]
+    fn test_tr() {
+        // BIP86 test vector
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let mnemonic = Mnemonic::from_str(words).unwrap();
+        let mut master =
+            MasterAccount::from_mnemonic(&mnemonic, 0, Network::Bitcoin, PASSPHRASE, None).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let mut account = Account::new(&mut unlocker, AccountAddressType::P2TR, 0, 0, 10).unwrap();
+        assert_eq!(account.derivation_path(0).to_string(), "m/86'/0'/0'/0/0");
+        assert_eq!(
+            taproot::encode_address(&account.get_key(0).unwrap().address),
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        // the receive address as to be shown is bech32m
+        let receive = account.next_key().unwrap().address_string();
+        assert_eq!(
+            receive,
+            "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr"
+        );
+        assert_eq!(
+            account.get_key(0).unwrap().address,
+            taproot::decode_address(&receive).unwrap()
+        );
+        master.add_account(account);
+
+        let source = master
+            .get((0, 0))
+            .unwrap()
+            .get_key(1)
+            .unwrap()
+            .address
+            .clone();
+        let input_transaction = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: source.script_pubkey(),
+                value: 5000000000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let txid = input_transaction.txid();
+        let spent = input_transaction.output[0].clone();
+        let output_key = source.script_pubkey()[2..].to_vec();
+
+        for (hash_type, taproot_hash_type) in [
+            (SigHashType::All, 0u8),
+            (SigHashType::AllPlusAnyoneCanPay, 0x81u8),
+        ]
+        .iter()
+        {
+            let mut spending_transaction = Transaction {
+                input: vec![TxIn {
+                    previous_output: OutPoint { txid, vout: 0 },
+                    sequence: RBF,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                }],
+                output: vec![TxOut {
+                    script_pubkey: source.script_pubkey(),
+                    value: 4999990000,
+                }],
+                lock_time: 0,
+                version: 2,
+            };
+            assert_eq!(
+                master
+                    .sign(
+                        &mut spending_transaction,
+                        *hash_type,
+                        &(|_| Some(spent.clone())),
+                        &mut unlocker
+                    )
+                    .unwrap(),
+                1
+            );
+            let witness = &spending_transaction.input[0].witness;
+            assert_eq!(witness.len(), 1);
+            let signature = &witness[0];
+            if *taproot_hash_type == 0 {
+                assert_eq!(signature.len(), 64);
+            } else {
+                assert_eq!(signature.len(), 65);
+                assert_eq!(signature[64], *taproot_hash_type);
+            }
+            let sighash = taproot::sighash(
+                &spending_transaction,
+                0,
+                std::slice::from_ref(&spent),
+                *taproot_hash_type,
+            )
+            .unwrap();
+            let context = SecpContext::new();
+            context
+                .schnorr_verify(&sighash[..], &signature[..64], &output_key)
+                .unwrap();
+        }
+    }
+
     #[test]
     fn crosscheck_with_hardware_wallet() {
         let words = "announce damage viable ticket engage curious yellow ten clock finish burden orient faculty rigid smile host offer affair suffer slogan mercy another switch park";
@@ -0,0 +1,765 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Reusable payment codes (BIP47)
+//!
+//! A static payment code at m / 47' / coin_type' / account' that can be published.
+//! A payer announces itself once with a notification transaction, then both sides derive
+//! fresh addresses of the contact through ECDH that no one else can link to the code.
+//!
+
+use std::{fmt, str::FromStr};
+
+use bitcoin::{
+    blockdata::{
+        opcodes::all,
+        script::{Builder, Instruction},
+        transaction::SigHashType,
+    },
+    consensus::encode::serialize,
+    network::constants::Network,
+    util::base58,
+    util::bip32::{ChainCode, ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey},
+    Address, OutPoint, PrivateKey, PublicKey, Script, Transaction, TxIn, TxOut,
+};
+use bitcoin_hashes::{hmac, sha256, sha512, Hash, HashEngine};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use account::{coin_type, Account, AccountAddressType, MasterAccount, Unlocker};
+use coins::Coin;
+use context::SecpContext;
+use error::Error;
+
+/// version 1 payment codes
+const VERSION: u8 = 0x01;
+/// base58 prefix of payment codes, they start with PM8T
+const PREFIX: u8 = 0x47;
+/// BIP43 purpose of payment codes
+const PURPOSE: u32 = 47;
+/// amount paid to the notification address
+const NOTIFICATION_AMOUNT: u64 = 546;
+/// smallest change output created
+const DUST: u64 = 546;
+const RBF: u32 = 0xffff_fffd;
+
+/// a version 1 payment code
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PaymentCode {
+    public: PublicKey,
+    chain_code: ChainCode,
+}
+
+impl PaymentCode {
+    /// the payment code of an extended public key
+    pub fn from_extended(key: &ExtendedPubKey) -> PaymentCode {
+        PaymentCode {
+            public: key.public_key,
+            chain_code: key.chain_code,
+        }
+    }
+
+    /// parse the 80 bytes of a payment code
+    pub fn from_bytes(data: &[u8]) -> Result<PaymentCode, Error> {
+        if data.len() != 80 {
+            return Err(Error::PaymentCode("payment code is not 80 bytes"));
+        }
+        if data[0] != VERSION {
+            return Err(Error::PaymentCode("unsupported payment code version"));
+        }
+        if data[2] != 0x02 && data[2] != 0x03 {
+            return Err(Error::PaymentCode("payment code key is not compressed"));
+        }
+        Ok(PaymentCode {
+            public: PublicKey::from_slice(&data[2..35])
+                .map_err(|_| Error::PaymentCode("invalid payment code key"))?,
+            chain_code: ChainCode::from(&data[35..67]),
+        })
+    }
+
+    /// version, features, key, chain code and reserved bytes
+    pub fn to_bytes(&self) -> [u8; 80] {
+        let mut data = [0u8; 80];
+        data[0] = VERSION;
+        data[2..35].copy_from_slice(self.public.to_bytes().as_slice());
+        data[35..67].copy_from_slice(&self.chain_code[..]);
+        data
+    }
+
+    /// the i-th public key of the payment code
+    pub fn key(&self, context: &SecpContext, i: u32) -> Result<PublicKey, Error> {
+        Ok(context
+            .public_child(&self.extended(), ChildNumber::Normal { index: i })?
+            .public_key)
+    }
+
+    /// the address notification transactions pay to
+    pub fn notification_address(&self, network: Network) -> Result<Address, Error> {
+        Ok(Address::p2pkh(&self.key(&SecpContext::new(), 0)?, network))
+    }
+
+    fn extended(&self) -> ExtendedPubKey {
+        ExtendedPubKey {
+            network: Network::Bitcoin,
+            depth: 3,
+            parent_fingerprint: Default::default(),
+            child_number: ChildNumber::Normal { index: 0 },
+            public_key: self.public,
+            chain_code: self.chain_code,
+        }
+    }
+}
+
+impl fmt::Display for PaymentCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut data = vec![PREFIX];
+        data.extend_from_slice(&self.to_bytes()[..]);
+        write!(f, "{}", base58::check_encode_slice(data.as_slice()))
+    }
+}
+
+impl FromStr for PaymentCode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PaymentCode, Error> {
+        let data = base58::from_check(s)?;
+        if data.first() != Some(&PREFIX) {
+            return Err(Error::PaymentCode("not a payment code"));
+        }
+        PaymentCode::from_bytes(&data[1..])
+    }
+}
+
+/// stored in its string form
+impl Serialize for PaymentCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PaymentCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PaymentCode, D::Error> {
+        PaymentCode::from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// m / 47' / coin_type' / account'
+pub fn payment_code_path(network: Network, account: u32) -> DerivationPath {
+    DerivationPath::from(vec![
+        ChildNumber::Hardened { index: PURPOSE },
+        ChildNumber::Hardened {
+            index: coin_type(network),
+        },
+        ChildNumber::Hardened { index: account },
+    ])
+}
+
+impl MasterAccount {
+    /// our payment code of an account
+    pub fn payment_code(
+        &self,
+        unlocker: &mut Unlocker,
+        account: u32,
+    ) -> Result<PaymentCode, Error> {
+        let key = self.payment_code_key(unlocker, account)?;
+        Ok(PaymentCode::from_extended(
+            &unlocker.context().extended_public_from_private(&key),
+        ))
+    }
+
+    /// a signed notification transaction announcing our payment code of an account to a contact
+    /// the designated coin must be of a P2PKH, P2SHWPKH or P2WPKH account, its key blinds the code
+    /// the rest of the coin less fee goes to change
+    pub fn notification_transaction(
+        &self,
+        unlocker: &mut Unlocker,
+        account: u32,
+        to: &PaymentCode,
+        designated: (OutPoint, Coin),
+        change: Script,
+        fee: u64,
+    ) -> Result<Transaction, Error> {
+        let (point, coin) = designated;
+        let derivation = &coin.derivation;
+        let address_type = self
+            .get((derivation.account, derivation.sub))
+            .ok_or(Error::PaymentCode("designated coin is not of this master"))?
+            .address_type();
+        match address_type {
+            AccountAddressType::P2PKH
+            | AccountAddressType::P2SHWPKH
+            | AccountAddressType::P2WPKH
+                if !derivation.imported => {}
+            _ => {
+                return Err(Error::PaymentCode(
+                    "designated coin must be of a P2PKH, P2SHWPKH or P2WPKH account",
+                ))
+            }
+        }
+        if coin.output.value < NOTIFICATION_AMOUNT + fee {
+            return Err(Error::PaymentCode("designated coin does not cover the fee"));
+        }
+        let context = unlocker.context();
+        let designated_key = unlocker.unlock_derivation(address_type, derivation)?;
+        let mask = blinding_mask(&context, &to.key(&context, 0)?, &designated_key, &point)?;
+        let blinded = blind(&self.payment_code(unlocker, account)?.to_bytes(), &mask);
+
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: point,
+                script_sig: Script::new(),
+                sequence: RBF,
+                witness: Vec::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: NOTIFICATION_AMOUNT,
+                    script_pubkey: to
+                        .notification_address(self.master_public().network)?
+                        .script_pubkey(),
+                },
+                TxOut {
+                    value: 0,
+                    script_pubkey: Builder::new()
+                        .push_opcode(all::OP_RETURN)
+                        .push_slice(&blinded[..])
+                        .into_script(),
+                },
+            ],
+        };
+        let rest = coin.output.value - NOTIFICATION_AMOUNT - fee;
+        if rest >= DUST {
+            transaction.output.push(TxOut {
+                value: rest,
+                script_pubkey: change,
+            });
+        }
+        let resolver = |p: &OutPoint| {
+            if *p == point {
+                Some(coin.output.clone())
+            } else {
+                None
+            }
+        };
+        if self.sign(&mut transaction, SigHashType::All, &resolver, unlocker)? == 0 {
+            return Err(Error::PaymentCode("designated coin could not be signed"));
+        }
+        Ok(transaction)
+    }
+
+    /// the payment code of a contact announced by a notification transaction
+    /// to our payment code of an account, None if the transaction does not notify us
+    pub fn parse_notification(
+        &self,
+        unlocker: &mut Unlocker,
+        account: u32,
+        transaction: &Transaction,
+    ) -> Result<Option<PaymentCode>, Error> {
+        let context = unlocker.context();
+        let key = self.payment_code_key(unlocker, account)?;
+        let notification = context
+            .private_child(&key, ChildNumber::Normal { index: 0 })?
+            .private_key;
+        let address = Address::p2pkh(
+            &context.public_from_private(&notification),
+            self.master_public().network,
+        );
+        if !transaction
+            .output
+            .iter()
+            .any(|o| o.script_pubkey == address.script_pubkey())
+        {
+            return Ok(None);
+        }
+        let blinded = transaction
+            .output
+            .iter()
+            .find_map(|o| op_return_data(&o.script_pubkey))
+            .ok_or(Error::PaymentCode("notification has no payment code"))?;
+        // the first input exposing a key is the designated input
+        let (point, designated) = transaction
+            .input
+            .iter()
+            .find_map(|i| exposed_key(i).map(|k| (i.previous_output, k)))
+            .ok_or(Error::PaymentCode("notification has no designated input"))?;
+        let mask = blinding_mask(&context, &designated, &notification, &point)?;
+        Ok(Some(PaymentCode::from_bytes(&blind(&blinded, &mask)[..])?))
+    }
+
+    /// the i-th address we pay a contact to from our payment code of an account
+    /// BIP47 uses P2PKH, P2SHWPKH and P2WPKH need a contact that registers them
+    pub fn send_address(
+        &self,
+        unlocker: &mut Unlocker,
+        account: u32,
+        to: &PaymentCode,
+        i: u32,
+        address_type: AccountAddressType,
+    ) -> Result<Address, Error> {
+        let context = unlocker.context();
+        let key = self.payment_code_key(unlocker, account)?;
+        let ours = context
+            .private_child(&key, ChildNumber::Normal { index: 0 })?
+            .private_key;
+        let mut theirs = to.key(&context, i)?;
+        let tweak = shared_tweak(&context, &theirs, &ours)?;
+        context.tweak_exp_add(&mut theirs, &tweak[..])?;
+        let network = self.master_public().network;
+        match address_type {
+            AccountAddressType::P2PKH => Ok(Address::p2pkh(&theirs, network)),
+            AccountAddressType::P2SHWPKH => Ok(Address::p2shwpkh(&theirs, network)),
+            AccountAddressType::P2WPKH => Ok(Address::p2wpkh(&theirs, network)),
+            _ => Err(Error::PaymentCode(
+                "payment code addresses are P2PKH, P2SHWPKH or P2WPKH",
+            )),
+        }
+    }
+
+    /// register the first count addresses a contact pays us to at our payment code of an account
+    /// the keys are held in an account of their own, created if not yet there,
+    /// so coins paid to them are found and spent as those of any other account
+    /// register more as payments arrive, e.g. keep count ahead of Account::used
+    /// the account is bound to the payment code, it does not derive keys on its own
+    pub fn register_payment_code(
+        &mut self,
+        unlocker: &mut Unlocker,
+        account: u32,
+        from: &PaymentCode,
+        address_type: AccountAddressType,
+        contact: (u32, u32),
+        count: u32,
+    ) -> Result<(), Error> {
+        let path = payment_code_path(self.master_public().network, account);
+        match self.get(contact) {
+            Some(existing) => {
+                if existing.path() != Some(&path)
+                    || existing.address_type() != address_type
+                    || existing.payment_code() != Some(from)
+                {
+                    return Err(Error::PaymentCode(
+                        "account is not of the payment code of this contact",
+                    ));
+                }
+            }
+            None => {
+                match address_type {
+                    AccountAddressType::P2PKH
+                    | AccountAddressType::P2SHWPKH
+                    | AccountAddressType::P2WPKH => {}
+                    _ => {
+                        return Err(Error::PaymentCode(
+                            "payment code addresses are P2PKH, P2SHWPKH or P2WPKH",
+                        ))
+                    }
+                }
+                let mut receiver = Account::new_with_path(
+                    unlocker,
+                    address_type,
+                    contact.0,
+                    contact.1,
+                    path.clone(),
+                    0,
+                )?;
+                receiver.set_payment_code(*from);
+                self.add_account(receiver);
+            }
+        }
+        let context = unlocker.context();
+        let key = unlocker.path_key(&path)?;
+        let theirs = from.key(&context, 0)?;
+        let receiver = self.get_mut(contact).expect("added above");
+        for i in receiver.instantiated().len() as u32..count {
+            let ours = context
+                .private_child(&key, ChildNumber::Normal { index: i })?
+                .private_key;
+            let tweak = shared_tweak(&context, &theirs, &ours)?;
+            receiver.add_tweaked_key(&tweak[..])?;
+        }
+        Ok(())
+    }
+
+    fn payment_code_key(
+        &self,
+        unlocker: &mut Unlocker,
+        account: u32,
+    ) -> Result<ExtendedPrivKey, Error> {
+        unlocker.path_key(&payment_code_path(self.master_public().network, account))
+    }
+}
+
+/// tweak of a payment code key shared by the contacts: SHA256(x of ECDH)
+fn shared_tweak(
+    context: &SecpContext,
+    public: &PublicKey,
+    private: &PrivateKey,
+) -> Result<sha256::Hash, Error> {
+    Ok(sha256::Hash::hash(
+        &context.shared_secret_x(public, private)?[..],
+    ))
+}
+
+/// HMAC-SHA512 keyed with the designated outpoint of the x of ECDH
+fn blinding_mask(
+    context: &SecpContext,
+    public: &PublicKey,
+    private: &PrivateKey,
+    point: &OutPoint,
+) -> Result<[u8; 64], Error> {
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(serialize(point).as_slice());
+    engine.input(&context.shared_secret_x(public, private)?[..]);
+    let mut mask = [0u8; 64];
+    mask.copy_from_slice(&hmac::Hmac::<sha512::Hash>::from_engine(engine)[..]);
+    Ok(mask)
+}
+
+/// blind or unblind the x coordinate and chain code of a payment code
+fn blind(code: &[u8; 80], mask: &[u8; 64]) -> [u8; 80] {
+    let mut blinded = *code;
+    for (b, m) in blinded[3..67].iter_mut().zip(mask.iter()) {
+        *b ^= *m;
+    }
+    blinded
+}
+
+fn op_return_data(script: &Script) -> Option<[u8; 80]> {
+    let mut instructions = script.iter(true);
+    match (
+        instructions.next(),
+        instructions.next(),
+        instructions.next(),
+    ) {
+        (Some(Instruction::Op(all::OP_RETURN)), Some(Instruction::PushBytes(data)), None)
+            if data.len() == 80 =>
+        {
+            let mut code = [0u8; 80];
+            code.copy_from_slice(data);
+            Some(code)
+        }
+        _ => None,
+    }
+}
+
+/// the key of an input spending a single key, the last element of its witness or script
+fn exposed_key(input: &TxIn) -> Option<PublicKey> {
+    let last = match input.witness.last() {
+        Some(element) => Some(element.as_slice()),
+        None => input.script_sig.iter(true).last().and_then(|i| match i {
+            Instruction::PushBytes(data) => Some(data),
+            _ => None,
+        }),
+    };
+    last.filter(|k| k.len() == 33)
+        .and_then(|k| PublicKey::from_slice(k).ok())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{util::hash::MerkleRoot, Block, BlockHeader};
+    use bitcoin_hashes::sha256d;
+
+    use coins::Coins;
+    use mnemonic::Mnemonic;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const ALICE: &str =
+        "response seminar brave tip suit recall often sound stick owner lottery motion";
+    const BOB: &str =
+        "reward upper indicate eight swift arch injury crystal super wrestle already dentist";
+
+    fn master(words: &str) -> (MasterAccount, Unlocker) {
+        let master = MasterAccount::from_mnemonic(
+            &Mnemonic::from_str(words).unwrap(),
+            0,
+            Network::Bitcoin,
+            PASSPHRASE,
+            None,
+        )
+        .unwrap();
+        let unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        (master, unlocker)
+    }
+
+    fn block(transaction: Transaction) -> Block {
+        let coinbase = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: Vec::new(),
+            lock_time: 0,
+            version: 2,
+        };
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                time: 0,
+                nonce: 0,
+                bits: 0x1d00ffff,
+                prev_blockhash: sha256d::Hash::default(),
+                merkle_root: sha256d::Hash::default(),
+            },
+            txdata: vec![coinbase, transaction],
+        };
+        block.header.merkle_root = block.merkle_root();
+        block
+    }
+
+    #[test]
+    fn bip47_vectors() {
+        let (alice, mut alice_unlocker) = master(ALICE);
+        let (mut bob, mut bob_unlocker) = master(BOB);
+        let alice_code = alice.payment_code(&mut alice_unlocker, 0).unwrap();
+        let bob_code = bob.payment_code(&mut bob_unlocker, 0).unwrap();
+        assert_eq!(alice_code.to_string(), "PM8TJTLJbPRGxSbc8EJi42Wrr6QbNSaSSVJ5Y3E4pbCYiTHUskHg13935Ubb7q8tx9GVbh2UuRnBc3WSyJHhUrw8KhprKnn9eDznYGieTzFcwQRya4GA");
+        assert_eq!(
+            alice_code
+                .notification_address(Network::Bitcoin)
+                .unwrap()
+                .to_string(),
+            "1JDdmqFLhpzcUwPeinhJbUPw4Co3aWLyzW"
+        );
+        assert_eq!(bob_code.to_string(), "PM8TJS2JxQ5ztXUpBBRnpTbcUXbUHy2T1abfrb3KkAAtMEGNbey4oumH7Hc578WgQJhPjBxteQ5GHHToTYHE3A1w6p7tU6KSoFmWBVbFGjKPisZDbP97");
+        assert_eq!(
+            bob_code
+                .notification_address(Network::Bitcoin)
+                .unwrap()
+                .to_string(),
+            "1ChvUUvht2hUQufHBXF8NgLhW8SwE2ecGV"
+        );
+        assert_eq!(
+            PaymentCode::from_str(&alice_code.to_string()).unwrap(),
+            alice_code
+        );
+        assert!(PaymentCode::from_str("xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8").is_err());
+
+        let first = alice
+            .send_address(
+                &mut alice_unlocker,
+                0,
+                &bob_code,
+                0,
+                AccountAddressType::P2PKH,
+            )
+            .unwrap();
+        assert_eq!(first.to_string(), "141fi7TY3h936vRUKh1qfUZr8rSBuYbVBK");
+        bob.register_payment_code(
+            &mut bob_unlocker,
+            0,
+            &alice_code,
+            AccountAddressType::P2PKH,
+            (1, 0),
+            3,
+        )
+        .unwrap();
+        let registered = bob.get((1, 0)).unwrap();
+        assert_eq!(registered.instantiated().len(), 3);
+        assert_eq!(registered.get_key(0).unwrap().address, first);
+        // a different address type is an other account
+        assert!(bob
+            .register_payment_code(
+                &mut bob_unlocker,
+                0,
+                &alice_code,
+                AccountAddressType::P2WPKH,
+                (1, 0),
+                3,
+            )
+            .is_err());
+        // registering again only adds the missing keys
+        bob.register_payment_code(
+            &mut bob_unlocker,
+            0,
+            &alice_code,
+            AccountAddressType::P2PKH,
+            (1, 0),
+            5,
+        )
+        .unwrap();
+        assert_eq!(bob.get((1, 0)).unwrap().instantiated().len(), 5);
+        // the account is bound to the payment code of its contact
+        assert!(bob
+            .register_payment_code(
+                &mut bob_unlocker,
+                0,
+                &bob_code,
+                AccountAddressType::P2PKH,
+                (1, 0),
+                6,
+            )
+            .is_err());
+        // keys of the payment code path are not handed out untweaked
+        assert!(bob.get_mut((1, 0)).unwrap().next_key().is_err());
+        assert!(bob.do_look_ahead((1, 0), Some(4)).unwrap().is_empty());
+        assert_eq!(bob.get((1, 0)).unwrap().instantiated().len(), 5);
+        let stored = serde_json::to_string(&bob).unwrap();
+        let restored: MasterAccount = serde_json::from_str(&stored).unwrap();
+        assert_eq!(
+            restored.get((1, 0)).unwrap().payment_code(),
+            Some(&alice_code)
+        );
+        for i in 0..5 {
+            assert_eq!(
+                bob.get((1, 0)).unwrap().get_key(i).unwrap().address,
+                alice
+                    .send_address(
+                        &mut alice_unlocker,
+                        0,
+                        &bob_code,
+                        i,
+                        AccountAddressType::P2PKH
+                    )
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn notify_and_pay() {
+        let (mut alice, mut alice_unlocker) = master(ALICE);
+        let (mut bob, mut bob_unlocker) = master(BOB);
+        let bob_code = bob.payment_code(&mut bob_unlocker, 0).unwrap();
+        alice.add_account(
+            Account::new(&mut alice_unlocker, AccountAddressType::P2WPKH, 0, 0, 2).unwrap(),
+        );
+        let funded = alice.get((0, 0)).unwrap().get_key(0).unwrap().clone();
+        let designated = (
+            OutPoint {
+                txid: sha256d::Hash::hash(b"funding"),
+                vout: 1,
+            },
+            Coin {
+                output: TxOut {
+                    script_pubkey: funded.address.script_pubkey(),
+                    value: 100000,
+                },
+                derivation: alice
+                    .get_derivation(&funded.address.script_pubkey())
+                    .unwrap(),
+            },
+        );
+        let change = alice
+            .get((0, 0))
+            .unwrap()
+            .get_key(1)
+            .unwrap()
+            .address
+            .script_pubkey();
+        let notification = alice
+            .notification_transaction(
+                &mut alice_unlocker,
+                0,
+                &bob_code,
+                designated.clone(),
+                change,
+                1000,
+            )
+            .unwrap();
+        assert_eq!(notification.output.len(), 3);
+        assert_eq!(notification.output[2].value, 100000 - 546 - 1000);
+        notification
+            .verify(|_| Some(designated.1.output.clone()))
+            .unwrap();
+
+        // only Bob can tell who notified him
+        let alice_code = alice.payment_code(&mut alice_unlocker, 0).unwrap();
+        assert_eq!(
+            bob.parse_notification(&mut bob_unlocker, 0, &notification)
+                .unwrap(),
+            Some(alice_code)
+        );
+        assert_eq!(
+            bob.parse_notification(&mut bob_unlocker, 1, &notification)
+                .unwrap(),
+            None
+        );
+        let blinded = op_return_data(&notification.output[1].script_pubkey).unwrap();
+        assert_ne!(&blinded[..], &alice_code.to_bytes()[..]);
+
+        // Bob finds and spends a payment to a segwit address of Alice
+        bob.register_payment_code(
+            &mut bob_unlocker,
+            0,
+            &alice_code,
+            AccountAddressType::P2WPKH,
+            (1, 0),
+            5,
+        )
+        .unwrap();
+        let address = alice
+            .send_address(
+                &mut alice_unlocker,
+                0,
+                &bob_code,
+                3,
+                AccountAddressType::P2WPKH,
+            )
+            .unwrap();
+        let payment = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: address.script_pubkey(),
+                value: 50000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let mut coins = Coins::new();
+        assert!(coins.process(&mut bob, &block(payment.clone())));
+        assert_eq!(coins.confirmed_balance(), 50000);
+        assert_eq!(bob.get((1, 0)).unwrap().used(), 4);
+
+        let mut spending = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: payment.txid(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: payment.output.clone(),
+            lock_time: 0,
+            version: 2,
+        };
+        assert_eq!(
+            bob.sign(
+                &mut spending,
+                SigHashType::All,
+                &(|_: &OutPoint| Some(payment.output[0].clone())),
+                &mut bob_unlocker
+            )
+            .unwrap(),
+            1
+        );
+        spending
+            .verify(|_| Some(payment.output[0].clone()))
+            .unwrap();
+    }
+}
@@ -21,14 +21,15 @@
 use std::collections::HashMap;
 
 use bitcoin::Block;
-use bitcoin::{OutPoint, Script, Transaction, TxOut};
+use bitcoin::{OutPoint, Transaction, TxOut};
 use bitcoin_hashes::sha256d;
 use rand::thread_rng;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use account::{KeyDerivation, MasterAccount};
+use account::{KeyDerivation, MasterAccount, STORAGE_VERSION};
 use proved::ProvedTransaction;
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 /// a coin is defined by the spendable output
 /// the key derivation that allows to spend it
 pub struct Coin {
@@ -47,6 +48,53 @@ pub struct Coins {
     proofs: HashMap<sha256d::Hash, ProvedTransaction>,
 }
 
+/// storage format of the coins as written
+#[derive(Serialize)]
+struct CoinsRef<'a> {
+    version: u32,
+    unconfirmed: &'a HashMap<OutPoint, Coin>,
+    confirmed: &'a HashMap<OutPoint, Coin>,
+    proofs: &'a HashMap<sha256d::Hash, ProvedTransaction>,
+}
+
+/// storage format of the coins as read
+#[derive(Deserialize)]
+struct StoredCoins {
+    version: u32,
+    unconfirmed: HashMap<OutPoint, Coin>,
+    confirmed: HashMap<OutPoint, Coin>,
+    proofs: HashMap<sha256d::Hash, ProvedTransaction>,
+}
+
+impl Serialize for Coins {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        CoinsRef {
+            version: STORAGE_VERSION,
+            unconfirmed: &self.unconfirmed,
+            confirmed: &self.confirmed,
+            proofs: &self.proofs,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Coins {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Coins, D::Error> {
+        let stored = StoredCoins::deserialize(deserializer)?;
+        if stored.version != STORAGE_VERSION {
+            return Err(de::Error::custom(format!(
+                "unknown coins storage version {}",
+                stored.version
+            )));
+        }
+        Ok(Coins {
+            unconfirmed: stored.unconfirmed,
+            confirmed: stored.confirmed,
+            proofs: stored.proofs,
+        })
+    }
+}
+
 impl Coins {
     pub fn new() -> Coins {
         Coins {
@@ -76,33 +124,15 @@ impl Coins {
         master_account: &mut MasterAccount,
         transaction: &Transaction,
     ) -> bool {
-        let mut scripts: HashMap<Script, KeyDerivation> = master_account.get_scripts().collect();
         let mut modified = false;
         for input in transaction.input.iter() {
             modified |= self.remove_confirmed(&input.previous_output);
         }
         for (vout, output) in transaction.output.iter().enumerate() {
-            let mut lookahead = Vec::new();
-            if let Some(d) = scripts.get(&output.script_pubkey) {
-                lookahead = master_account
-                    .get_mut((d.account, d.sub))
-                    .unwrap()
-                    .do_look_ahead(Some(d.kix))
-                    .unwrap()
-                    .iter()
-                    .map(move |(kix, s)| {
-                        (
-                            s.clone(),
-                            KeyDerivation {
-                                kix: *kix,
-                                account: d.account,
-                                sub: d.sub,
-                                tweak: d.tweak.clone(),
-                                csv: d.csv.clone(),
-                            },
-                        )
-                    })
-                    .collect();
+            if let Some(d) = master_account.get_derivation(&output.script_pubkey) {
+                master_account
+                    .do_look_ahead((d.account, d.sub), Some(d.kix))
+                    .unwrap();
                 self.unconfirmed.insert(
                     OutPoint {
                         txid: transaction.txid(),
@@ -110,14 +140,11 @@ impl Coins {
                     },
                     Coin {
                         output: output.clone(),
-                        derivation: d.clone(),
+                        derivation: d,
                     },
                 );
                 modified = true;
             }
-            for (s, d) in lookahead {
-                scripts.insert(s.clone(), d);
-            }
         }
         modified
     }
@@ -214,8 +241,6 @@ impl Coins {
     /// there is nothing in them you would care (this will be easy to tell with committed BIP158
     /// filters, but we are not yet there)
     pub fn process(&mut self, master_account: &mut MasterAccount, block: &Block) -> bool {
-        let mut scripts: HashMap<Script, KeyDerivation> = master_account.get_scripts().collect();
-
         let mut modified = false;
         for (txnr, tx) in block.txdata.iter().enumerate() {
             if txnr > 0 {
@@ -225,27 +250,10 @@ impl Coins {
                 }
             }
             for (vout, output) in tx.output.iter().enumerate() {
-                let mut lookahead = Vec::new();
-                if let Some(d) = scripts.get(&output.script_pubkey) {
-                    lookahead = master_account
-                        .get_mut((d.account, d.sub))
-                        .unwrap()
-                        .do_look_ahead(Some(d.kix))
-                        .unwrap()
-                        .iter()
-                        .map(move |(kix, s)| {
-                            (
-                                s.clone(),
-                                KeyDerivation {
-                                    kix: *kix,
-                                    account: d.account,
-                                    sub: d.sub,
-                                    tweak: d.tweak.clone(),
-                                    csv: d.csv.clone(),
-                                },
-                            )
-                        })
-                        .collect();
+                if let Some(d) = master_account.get_derivation(&output.script_pubkey) {
+                    master_account
+                        .do_look_ahead((d.account, d.sub), Some(d.kix))
+                        .unwrap();
                     let point = OutPoint {
                         txid: tx.txid(),
                         vout: vout as u32,
@@ -255,7 +263,7 @@ impl Coins {
                         point,
                         Coin {
                             output: output.clone(),
-                            derivation: d.clone(),
+                            derivation: d,
                         },
                     );
                     self.proofs
@@ -263,9 +271,6 @@ impl Coins {
                         .or_insert(ProvedTransaction::new(block, txnr));
                     modified = true;
                 }
-                for (s, d) in lookahead {
-                    scripts.insert(s.clone(), d);
-                }
             }
         }
         modified
@@ -411,6 +416,9 @@ mod test {
         let next = mine(&genesis.bitcoin_hash(), 1, miner);
         coins.process(&mut master, &next);
         assert_eq!(coins.confirmed_balance(), NEW_COINS);
+        let restored: Coins =
+            serde_json::from_str(&serde_json::to_string(&coins).unwrap()).unwrap();
+        assert!(restored == coins);
         coins.unwind_tip(&next.bitcoin_hash());
         assert_eq!(coins.confirmed_balance(), 0);
     }
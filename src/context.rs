@@ -21,15 +21,33 @@ use bitcoin::{
     util::bip32::{ChildNumber, ExtendedPrivKey, ExtendedPubKey},
     PrivateKey, PublicKey,
 };
-use secp256k1::{All, Message, Secp256k1, Signature};
+use rand::{thread_rng, RngCore};
+use secp256k1::{
+    key::{PublicKey as SecpPublicKey, SecretKey},
+    recovery::{RecoverableSignature, RecoveryId},
+    All, Message, Secp256k1, Signature,
+};
 
 use account::Seed;
 use error::Error;
+use taproot::{tagged_hash, tweak};
+
+/// order of the curve less one, multiplying with it negates
+const ORDER_LESS_ONE: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x40,
+];
 
 pub struct SecpContext {
     secp: Secp256k1<All>,
 }
 
+impl Default for SecpContext {
+    fn default() -> SecpContext {
+        SecpContext::new()
+    }
+}
+
 impl SecpContext {
     pub fn new() -> SecpContext {
         SecpContext {
@@ -78,6 +96,116 @@ impl SecpContext {
         Ok(self.secp.sign(&Message::from_slice(digest)?, &key.key))
     }
 
+    pub fn verify(
+        &self,
+        digest: &[u8],
+        signature: &Signature,
+        key: &PublicKey,
+    ) -> Result<(), Error> {
+        Ok(self
+            .secp
+            .verify(&Message::from_slice(digest)?, signature, &key.key)?)
+    }
+
+    /// public key of a compact signature with recovery id
+    pub fn recover(
+        &self,
+        digest: &[u8],
+        signature: &[u8],
+        recovery_id: u8,
+        compressed: bool,
+    ) -> Result<PublicKey, Error> {
+        let signature = RecoverableSignature::from_compact(
+            signature,
+            RecoveryId::from_i32(i32::from(recovery_id))?,
+        )?;
+        Ok(PublicKey {
+            compressed,
+            key: self
+                .secp
+                .recover(&Message::from_slice(digest)?, &signature)?,
+        })
+    }
+
+    /// BIP340 Schnorr signature of a digest
+    pub fn schnorr_sign(&self, digest: &[u8], key: &PrivateKey) -> Result<[u8; 64], Error> {
+        let mut aux = [0u8; 32];
+        thread_rng().fill_bytes(&mut aux);
+        self.schnorr_sign_with_aux(digest, key, &aux)
+    }
+
+    fn schnorr_sign_with_aux(
+        &self,
+        digest: &[u8],
+        key: &PrivateKey,
+        aux: &[u8; 32],
+    ) -> Result<[u8; 64], Error> {
+        let mut d = key.key;
+        let p = SecpPublicKey::from_secret_key(&self.secp, &d).serialize();
+        if p[0] == 0x03 {
+            d.mul_assign(&ORDER_LESS_ONE)?;
+        }
+        let mut masked = [0u8; 32];
+        for (m, (a, b)) in masked
+            .iter_mut()
+            .zip(d[..].iter().zip(tagged_hash("BIP0340/aux", &[aux]).iter()))
+        {
+            *m = a ^ b;
+        }
+        let mut k = scalar(&tagged_hash("BIP0340/nonce", &[&masked, &p[1..], digest])[..])?;
+        let r = SecpPublicKey::from_secret_key(&self.secp, &k).serialize();
+        if r[0] == 0x03 {
+            k.mul_assign(&ORDER_LESS_ONE)?;
+        }
+        let mut e = scalar(&tagged_hash("BIP0340/challenge", &[&r[1..], &p[1..], digest])[..])?;
+        e.mul_assign(&d[..])?;
+        k.add_assign(&e[..])?;
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&r[1..]);
+        signature[32..].copy_from_slice(&k[..]);
+        Ok(signature)
+    }
+
+    /// verify a BIP340 Schnorr signature with a 32 byte x-only public key
+    pub fn schnorr_verify(&self, digest: &[u8], signature: &[u8], key: &[u8]) -> Result<(), Error> {
+        if signature.len() != 64 || key.len() != 32 {
+            return Err(Error::SecpError(secp256k1::Error::InvalidSignature));
+        }
+        let mut p = lift_x(key)?;
+        let s = SecretKey::from_slice(&signature[32..])?;
+        let e = scalar(&tagged_hash("BIP0340/challenge", &[&signature[..32], key, digest])[..])?;
+        // R = s*G - e*P
+        p.mul_assign(&self.secp, &e[..])?;
+        p.mul_assign(&self.secp, &ORDER_LESS_ONE)?;
+        let r = SecpPublicKey::from_secret_key(&self.secp, &s)
+            .combine(&p)?
+            .serialize();
+        if r[0] != 0x02 || r[1..] != signature[..32] {
+            return Err(Error::SecpError(secp256k1::Error::InvalidSignature));
+        }
+        Ok(())
+    }
+
+    /// x-only output key of an internal key without script tree (BIP86)
+    pub fn taproot_output_key(&self, internal: &PublicKey) -> Result<[u8; 32], Error> {
+        let x = &internal.key.serialize()[1..];
+        let mut q = lift_x(x)?;
+        q.add_exp_assign(&self.secp, &tweak(x)[..])?;
+        let mut output_key = [0u8; 32];
+        output_key.copy_from_slice(&q.serialize()[1..]);
+        Ok(output_key)
+    }
+
+    /// tweak a private key to the one of its taproot output key (BIP86)
+    pub fn taproot_tweak_private(&self, key: &mut PrivateKey) -> Result<(), Error> {
+        let p = SecpPublicKey::from_secret_key(&self.secp, &key.key).serialize();
+        if p[0] == 0x03 {
+            key.key.mul_assign(&ORDER_LESS_ONE)?;
+        }
+        key.key.add_assign(&tweak(&p[1..])[..])?;
+        Ok(())
+    }
+
     pub fn tweak_add(&self, key: &mut PrivateKey, tweak: &[u8]) -> Result<(), Error> {
         key.key.add_assign(tweak)?;
         Ok(())
@@ -87,4 +215,91 @@ impl SecpContext {
         key.key.add_exp_assign(&self.secp, tweak)?;
         Ok(())
     }
+
+    /// x coordinate of the point shared by a private and a public key, as used by BIP47
+    pub fn shared_secret_x(
+        &self,
+        public: &PublicKey,
+        private: &PrivateKey,
+    ) -> Result<[u8; 32], Error> {
+        let mut point = public.key;
+        point.mul_assign(&self.secp, &private.key[..])?;
+        let mut x = [0u8; 32];
+        x.copy_from_slice(&point.serialize()[1..]);
+        Ok(x)
+    }
+}
+
+/// the point with even y for an x coordinate
+fn lift_x(x: &[u8]) -> Result<SecpPublicKey, Error> {
+    let mut compressed = [0x02u8; 33];
+    compressed[1..].copy_from_slice(x);
+    Ok(SecpPublicKey::from_slice(&compressed)?)
+}
+
+/// hash as a scalar, reduced modulo the curve order
+fn scalar(hash: &[u8]) -> Result<SecretKey, Error> {
+    let mut n = [0u8; 32];
+    n.copy_from_slice(&ORDER_LESS_ONE);
+    n[31] += 1;
+    let mut v = [0u8; 32];
+    v.copy_from_slice(hash);
+    if v >= n {
+        let mut borrow = 0i16;
+        for i in (0..32).rev() {
+            let d = i16::from(v[i]) - i16::from(n[i]) - borrow;
+            borrow = if d < 0 { 1 } else { 0 };
+            v[i] = (d & 0xff) as u8;
+        }
+    }
+    Ok(SecretKey::from_slice(&v)?)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::network::constants::Network;
+    use hex::decode;
+
+    use super::*;
+
+    #[test]
+    fn bip340_vectors() {
+        let context = SecpContext::new();
+        for (secret, public, aux, message, signature) in [
+            (
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA821525F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0",
+            ),
+            (
+                "B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+                "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+                "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE33418906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A",
+            ),
+        ]
+        .iter()
+        {
+            let key = PrivateKey {
+                compressed: true,
+                network: Network::Bitcoin,
+                key: SecretKey::from_slice(&decode(secret).unwrap()).unwrap(),
+            };
+            let mut a = [0u8; 32];
+            a.copy_from_slice(&decode(aux).unwrap());
+            let message = decode(message).unwrap();
+            let sig = context
+                .schnorr_sign_with_aux(&message, &key, &a)
+                .unwrap();
+            assert_eq!(sig.to_vec(), decode(signature).unwrap());
+            let public = decode(public).unwrap();
+            context.schnorr_verify(&message, &sig, &public).unwrap();
+            let mut wrong = message.clone();
+            wrong[0] ^= 1;
+            assert!(context.schnorr_verify(&wrong, &sig, &public).is_err());
+        }
+    }
 }
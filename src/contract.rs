@@ -0,0 +1,217 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Pay to contract
+//!
+//! Keys of P2WSH accounts tweaked with the hash of a contract document:
+//! tweak = SHA256(P || contract), the key is P + tweak * G.
+//! The proof lets a third party check that an address commits to the contract
+//! given the base key P, e.g. derived from the extended public key of the account.
+//!
+
+use bitcoin::{blockdata::script::Instruction, Address, PublicKey, Script};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+
+use account::{Account, AccountAddressType};
+use context::SecpContext;
+use error::Error;
+use template::ScriptTemplate;
+
+/// proof that an address of an account commits to a contract
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ContractProof {
+    /// position of the key in the account, its base key is compute_base_public_key(kix)
+    pub kix: u32,
+    /// the tweaked key
+    pub tweaked: PublicKey,
+    /// the script of the tweaked key
+    pub script_code: Script,
+    /// the address paying to the script
+    pub address: Address,
+}
+
+/// the tweak of a base key committing to a contract
+pub fn contract_tweak(base: &PublicKey, contract: &[u8]) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(base.to_bytes().as_slice());
+    engine.input(contract);
+    sha256::Hash::from_engine(engine)
+}
+
+impl Account {
+    /// add a key of a P2WSH account tweaked with a contract
+    /// its script is of the template of the account, a single key script if it has none
+    pub fn add_contract_key(&mut self, contract: &[u8]) -> Result<ContractProof, Error> {
+        let kix = self.instantiated().len() as u32;
+        let base = self.compute_base_public_key(kix)?;
+        let tweak = contract_tweak(&base, contract);
+        let template = self.template().unwrap_or(ScriptTemplate::SingleKey);
+        let kix = self.add_script_key(
+            |pk: &PublicKey, _| template.script(pk),
+            Some(&tweak[..]),
+            template.csv(),
+        )?;
+        let instantiated = self.get_key(kix).expect("just added");
+        Ok(ContractProof {
+            kix,
+            tweaked: instantiated.public,
+            script_code: instantiated.script_code.clone(),
+            address: instantiated.address.clone(),
+        })
+    }
+}
+
+impl ContractProof {
+    /// check that the address commits to the contract with the given base key
+    pub fn verify(&self, base: &PublicKey, contract: &[u8]) -> Result<(), Error> {
+        let tweak = contract_tweak(base, contract);
+        let mut tweaked = *base;
+        SecpContext::new().tweak_exp_add(&mut tweaked, &tweak[..])?;
+        if tweaked != self.tweaked {
+            return Err(Error::Contract(
+                "key is not the base key tweaked with the contract",
+            ));
+        }
+        let key = tweaked.to_bytes();
+        if !self.script_code.iter(true).any(|i| match i {
+            Instruction::PushBytes(k) => k == key.as_slice(),
+            _ => false,
+        }) {
+            return Err(Error::Contract("script does not use the tweaked key"));
+        }
+        let wsh = self.script_code.to_v0_p2wsh();
+        let script_pubkey = self.address.script_pubkey();
+        if script_pubkey != wsh && script_pubkey != wsh.to_p2sh() {
+            return Err(Error::Contract("address does not pay to the script"));
+        }
+        Ok(())
+    }
+
+    /// check that the address commits to the contract with the base key of an account
+    pub fn verify_for_account(&self, account: &Account, contract: &[u8]) -> Result<(), Error> {
+        match account.address_type() {
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => {}
+            _ => return Err(Error::Contract("not a P2WSH account")),
+        }
+        self.verify(&account.compute_base_public_key(self.kix)?, contract)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::util::bip32::ChildNumber;
+    use bitcoin::{network::constants::Network, OutPoint, SigHashType, Transaction, TxIn, TxOut};
+    use bitcoin_hashes::sha256d;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use coins::Coins;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const CONTRACT: &[u8] = b"Alice pays Bob 1 BTC for a bicycle";
+
+    #[test]
+    fn pay_to_contract() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let mut account =
+            Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 0, 0, 0).unwrap();
+        let proof = account.add_contract_key(CONTRACT).unwrap();
+        assert_eq!(proof.address, account.get_key(proof.kix).unwrap().address);
+        proof.verify_for_account(&account, CONTRACT).unwrap();
+        assert!(proof
+            .verify_for_account(&account, b"Alice pays Bob 1 BTC for a car")
+            .is_err());
+        let other_key = account.compute_base_public_key(1).unwrap();
+        assert!(proof.verify(&other_key, CONTRACT).is_err());
+
+        // a third party verifies with the extended public key of the account
+        let context = SecpContext::new();
+        let account_private = [4711, 1, 0]
+            .iter()
+            .fold(*unlocker.master_private(), |k, i| {
+                context
+                    .private_child(&k, ChildNumber::Hardened { index: *i })
+                    .unwrap()
+            });
+        let base = context
+            .public_child(
+                &context.extended_public_from_private(&account_private),
+                ChildNumber::Normal { index: 0 },
+            )
+            .unwrap();
+        let base = context
+            .public_child(&base, ChildNumber::Normal { index: proof.kix })
+            .unwrap()
+            .public_key;
+        proof.verify(&base, CONTRACT).unwrap();
+        let stored: ContractProof =
+            serde_json::from_str(&serde_json::to_string(&proof).unwrap()).unwrap();
+        assert_eq!(stored, proof);
+
+        // the committed coins are found and spent with the tweaked key
+        master.add_account(account);
+        let funding = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: proof.address.script_pubkey(),
+                value: 100000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let mut coins = Coins::new();
+        assert!(coins.process_unconfirmed_transaction(&mut master, &funding));
+        let mut spending = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: funding.txid(),
+                    vout: 0,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: funding.output.clone(),
+            lock_time: 0,
+            version: 2,
+        };
+        assert_eq!(
+            master
+                .sign(
+                    &mut spending,
+                    SigHashType::All,
+                    &(|_: &OutPoint| Some(funding.output[0].clone())),
+                    &mut unlocker
+                )
+                .unwrap(),
+            1
+        );
+        spending
+            .verify(|_| Some(funding.output[0].clone()))
+            .unwrap();
+    }
+}
@@ -0,0 +1,389 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Output script descriptors
+//!
+//! Export and import of accounts as BIP380 family descriptors
+//! pkh(), sh(wpkh()), wpkh(), wsh(pk()) and sh(wsh(pk()))
+//! Multisig accounts are exported as wsh(sortedmulti()) and sh(wsh(sortedmulti()))
+//!
+
+use std::str::FromStr;
+
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey, Fingerprint};
+
+use account::{coin_type, Account, AccountAddressType, MasterAccount};
+use context::SecpContext;
+use error::Error;
+use slip132;
+use template::ScriptTemplate;
+
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+impl MasterAccount {
+    /// output script descriptors of all accounts
+    pub fn descriptors(&self) -> Result<Vec<String>, Error> {
+        let fingerprint = self.master_public().fingerprint();
+        let mut accounts = self.accounts().iter().collect::<Vec<_>>();
+        accounts.sort_by_key(|(k, _)| **k);
+        accounts
+            .iter()
+            .map(|(_, a)| a.descriptor(fingerprint))
+            .collect()
+    }
+}
+
+impl Account {
+    /// output script descriptor of this account with key origin and checksum
+    /// master_fingerprint is the fingerprint of the master public key
+    /// P2WSH accounts can only be described if all their scripts are <key> OP_CHECKSIG
+    /// accounts of imported keys have no descriptor
+    pub fn descriptor(&self, master_fingerprint: Fingerprint) -> Result<String, Error> {
+        if self.is_imported() {
+            return Err(Error::Unsupported("descriptor of imported keys"));
+        }
+        let mut path: Vec<ChildNumber> = self.derivation_path(0).into();
+        path.pop();
+        let origin = path.iter().fold(master_fingerprint.to_string(), |o, c| {
+            format!("{}/{}", o, c)
+        });
+        let key = format!("[{}]{}/*", origin, self.master_public());
+        let descriptor = match self.address_type() {
+            AccountAddressType::P2PKH => format!("pkh({})", key),
+            AccountAddressType::P2SHWPKH => format!("sh(wpkh({}))", key),
+            AccountAddressType::P2WPKH => format!("wpkh({})", key),
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => {
+                for (kix, i) in self.instantiated().iter().enumerate() {
+                    if i.tweak.is_some()
+                        || i.csv.is_some()
+                        || i.script_code
+                            != ScriptTemplate::SingleKey
+                                .script(&self.compute_base_public_key(kix as u32)?)
+                    {
+                        return Err(Error::Unsupported(
+                            "descriptor of P2WSH account only for single key scripts",
+                        ));
+                    }
+                }
+                match self.address_type() {
+                    AccountAddressType::P2SHWSH(_) => format!("sh(wsh(pk({})))", key),
+                    _ => format!("wsh(pk({}))", key),
+                }
+            }
+            AccountAddressType::P2WSHMultisig => {
+                format!("wsh({})", self.sorted_multi(&key))
+            }
+            AccountAddressType::P2SHWSHMultisig => {
+                format!("sh(wsh({}))", self.sorted_multi(&key))
+            }
+            AccountAddressType::P2TR => format!("tr({})", key),
+        };
+        Ok(format!("{}#{}", descriptor, checksum(&descriptor)?))
+    }
+
+    /// sortedmulti() of own key expression and cosigner keys
+    fn sorted_multi(&self, key: &str) -> String {
+        self.cosigners().iter().fold(
+            format!("sortedmulti({},{}", self.threshold(), key),
+            |d, c| format!("{},{}/*", d, c),
+        ) + ")"
+    }
+
+    /// rebuild a watch-only account from an output script descriptor
+    /// multisig descriptors can not be imported
+    /// the key origin must be m / purpose' / coin_type' / account' / sub
+    /// either with the sub account key as [origin]xpub/*
+    /// or the account key as [origin]xpub/sub/*
+    pub fn from_descriptor(descriptor: &str, look_ahead: u32) -> Result<Account, Error> {
+        let descriptor = if let Some(pos) = descriptor.find('#') {
+            let (descriptor, check) = descriptor.split_at(pos);
+            if checksum(descriptor)? != check[1..] {
+                return Err(Error::Descriptor("checksum mismatch"));
+            }
+            descriptor
+        } else {
+            descriptor
+        };
+        let unwrap = |prefix: &str, suffix: &str| {
+            if descriptor.starts_with(prefix) && descriptor.ends_with(suffix) {
+                Some(&descriptor[prefix.len()..descriptor.len() - suffix.len()])
+            } else {
+                None
+            }
+        };
+        let (script_type, key) = if let Some(key) = unwrap("pkh(", ")") {
+            (AccountAddressType::P2PKH, key)
+        } else if let Some(key) = unwrap("sh(wpkh(", "))") {
+            (AccountAddressType::P2SHWPKH, key)
+        } else if let Some(key) = unwrap("wpkh(", ")") {
+            (AccountAddressType::P2WPKH, key)
+        } else if let Some(key) = unwrap("sh(wsh(pk(", ")))") {
+            (AccountAddressType::P2SHWSH(0), key)
+        } else if let Some(key) = unwrap("wsh(pk(", "))") {
+            (AccountAddressType::P2WSH(0), key)
+        } else if let Some(key) = unwrap("tr(", ")") {
+            (AccountAddressType::P2TR, key)
+        } else {
+            return Err(Error::Descriptor("unsupported descriptor"));
+        };
+
+        let (_, path, master_public) = parse_key(key)?;
+        if path.len() != 4 || !path[..3].iter().all(|c| c.is_hardened()) || !path[3].is_normal() {
+            return Err(Error::Descriptor(
+                "key path must be purpose'/coin_type'/account'/sub/*",
+            ));
+        }
+        let index = |c: &ChildNumber| match *c {
+            ChildNumber::Hardened { index } => index,
+            ChildNumber::Normal { index } => index,
+        };
+        let address_type = match script_type {
+            AccountAddressType::P2WSH(_) => AccountAddressType::P2WSH(index(&path[0])),
+            AccountAddressType::P2SHWSH(_) => AccountAddressType::P2SHWSH(index(&path[0])),
+            t => t,
+        };
+        // the purpose of wrapped P2WSH is read as native
+        let native = match address_type {
+            AccountAddressType::P2SHWSH(n) => AccountAddressType::P2WSH(n),
+            t => t,
+        };
+        if address_type.as_u32() != index(&path[0])
+            || AccountAddressType::from_u32(index(&path[0])) != native
+        {
+            return Err(Error::Descriptor("purpose does not match script type"));
+        }
+        if index(&path[1]) != coin_type(master_public.network) {
+            return Err(Error::Network);
+        }
+
+        let template = match address_type {
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => {
+                Some(ScriptTemplate::SingleKey)
+            }
+            _ => None,
+        };
+        Account::new_watch_only_sub(
+            &master_public,
+            address_type,
+            index(&path[2]),
+            index(&path[3]),
+            template,
+            look_ahead,
+        )
+    }
+}
+
+/// parse a key expression [fingerprint/origin]xpub/path/*
+/// returns the fingerprint, the full path and the key at the end of the path before /*
+fn parse_key(key: &str) -> Result<(Fingerprint, Vec<ChildNumber>, ExtendedPubKey), Error> {
+    if !key.starts_with('[') {
+        return Err(Error::Descriptor("key origin is missing"));
+    }
+    let end = key
+        .find(']')
+        .ok_or(Error::Descriptor("key origin is not closed"))?;
+    let mut origin = key[1..end].split('/');
+    let fingerprint = Fingerprint::from_str(origin.next().unwrap())
+        .map_err(|_| Error::Descriptor("invalid fingerprint"))?;
+    let mut path = origin
+        .map(ChildNumber::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if !key.ends_with("/*") {
+        return Err(Error::Descriptor("key must end with /*"));
+    }
+    let mut rest = key[end + 1..key.len() - 2].split('/');
+    // also accept keys with SLIP-132 versions as exported by some wallets
+    let (mut public, _) = slip132::decode_public(rest.next().unwrap())
+        .map_err(|_| Error::Descriptor("invalid extended public key"))?;
+    let context = SecpContext::new();
+    for c in rest {
+        let c = ChildNumber::from_str(c)?;
+        public = context.public_child(&public, c)?;
+        path.push(c);
+    }
+    Ok((fingerprint, path, public))
+}
+
+/// compute the checksum of a descriptor
+pub fn checksum(descriptor: &str) -> Result<String, Error> {
+    fn polymod(c: u64, value: u64) -> u64 {
+        const GENERATOR: [u64; 5] = [
+            0xf5dee51989,
+            0xa9fdca3312,
+            0x1bab10e32d,
+            0x3706b1677a,
+            0x644d626ffd,
+        ];
+        let top = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ value;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 != 0 {
+                c ^= g;
+            }
+        }
+        c
+    }
+
+    let mut c = 1u64;
+    let mut cls = 0u64;
+    let mut clscount = 0;
+    for ch in descriptor.chars() {
+        let pos = INPUT_CHARSET
+            .find(ch)
+            .ok_or(Error::Descriptor("invalid character"))? as u64;
+        c = polymod(c, pos & 31);
+        cls = cls * 3 + (pos >> 5);
+        clscount += 1;
+        if clscount == 3 {
+            c = polymod(c, cls);
+            cls = 0;
+            clscount = 0;
+        }
+    }
+    if clscount > 0 {
+        c = polymod(c, cls);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+    Ok((0..8)
+        .map(|j| CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize] as char)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{network::constants::Network, PublicKey};
+
+    use account::{MasterKeyEntropy, Unlocker};
+    use mnemonic::Mnemonic;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum("sh(multi(2,[00000000/111'/222]xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL,xpub68NZiKmJWnxxS6aaHmn81bvJeTESw724CRDs6HbuccFQN9Ku14VQrADWgqbhhTHBaohPX4CjNLf9fq9MYo6oDaPPLPxSb7gwQN3ih19Zm4Y/0))").unwrap(), "tjg09x5t");
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        for (n, t) in [
+            AccountAddressType::P2PKH,
+            AccountAddressType::P2SHWPKH,
+            AccountAddressType::P2WPKH,
+            AccountAddressType::P2TR,
+        ]
+        .iter()
+        .enumerate()
+        {
+            master.add_account(Account::new(&mut unlocker, *t, n as u32, 1, 10).unwrap());
+        }
+        let mut account =
+            Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 4, 0, 0).unwrap();
+        account
+            .add_script_key(
+                |pk: &PublicKey, _| ScriptTemplate::SingleKey.script(pk),
+                None,
+                None,
+            )
+            .unwrap();
+        master.add_account(account);
+        let mut account =
+            Account::new(&mut unlocker, AccountAddressType::P2SHWSH(4712), 5, 0, 0).unwrap();
+        account
+            .add_script_key(
+                |pk: &PublicKey, _| ScriptTemplate::SingleKey.script(pk),
+                None,
+                None,
+            )
+            .unwrap();
+        master.add_account(account);
+
+        let descriptors = master.descriptors().unwrap();
+        assert_eq!(descriptors.len(), 6);
+        assert!(descriptors[3].starts_with("tr("));
+        assert!(descriptors[5].starts_with("sh(wsh(pk("));
+        assert!(descriptors[0].starts_with(&format!(
+            "pkh([{}/44'/1'/0'/1]tpub",
+            master.master_public().fingerprint()
+        )));
+        for d in descriptors {
+            let imported = Account::from_descriptor(&d, 10).unwrap();
+            let original = master
+                .get((imported.account_number(), imported.sub_account_number()))
+                .unwrap();
+            assert_eq!(imported.address_type(), original.address_type());
+            assert!(imported.is_watch_only());
+            for (i, o) in imported.instantiated().iter().zip(original.instantiated()) {
+                assert_eq!(i.address, o.address);
+            }
+        }
+    }
+
+    #[test]
+    fn import_account_key() {
+        let words = "announce damage viable ticket engage curious yellow ten clock finish burden orient faculty rigid smile host offer affair suffer slogan mercy another switch park";
+        let master = MasterAccount::from_mnemonic(
+            &Mnemonic::from_str(words).unwrap(),
+            0,
+            Network::Bitcoin,
+            PASSPHRASE,
+            None,
+        )
+        .unwrap();
+        let unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let context = SecpContext::new();
+        let account_key = context.extended_public_from_private(
+            &context
+                .private_child(
+                    &context
+                        .private_child(
+                            &context
+                                .private_child(
+                                    unlocker.master_private(),
+                                    ChildNumber::Hardened { index: 84 },
+                                )
+                                .unwrap(),
+                            ChildNumber::Hardened { index: 0 },
+                        )
+                        .unwrap(),
+                    ChildNumber::Hardened { index: 0 },
+                )
+                .unwrap(),
+        );
+        let descriptor = format!(
+            "wpkh([{}/84h/0h/0h]{}/0/*)",
+            master.master_public().fingerprint(),
+            account_key
+        );
+        let account = Account::from_descriptor(&descriptor, 10).unwrap();
+        // this should be address of m/84'/0'/0'/0/0
+        assert_eq!(
+            account.get_key(0).unwrap().address.to_string(),
+            "bc1qlz2h9scgalmqj43d36f58dcxrrl7udu999gcp2"
+        );
+        assert!(Account::from_descriptor(&format!("{}#00000000", descriptor), 10).is_err());
+        assert!(Account::from_descriptor(&descriptor.replace("84h", "44h"), 10).is_err());
+    }
+}
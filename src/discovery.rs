@@ -0,0 +1,284 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Account discovery
+//!
+//! Restore the accounts of a master key from the chain as described in BIP44:
+//! accounts are scanned in order with a gap limit on both sub-chains,
+//! scanning stops at the first account without transactions
+//!
+
+use std::collections::HashSet;
+
+use bitcoin::{Block, Script};
+
+use account::{Account, AccountAddressType, MasterAccount, Unlocker};
+use coins::Coins;
+use error::Error;
+
+/// the gap limit recommended by BIP44
+pub const GAP_LIMIT: u32 = 20;
+
+/// address types scanned by default
+pub const DISCOVERY_ADDRESS_TYPES: [AccountAddressType; 3] = [
+    AccountAddressType::P2PKH,
+    AccountAddressType::P2SHWPKH,
+    AccountAddressType::P2WPKH,
+];
+
+/// a source of blocks to scan
+pub trait BlockSource {
+    /// blocks that might pay to any of the scripts, in ascending height order
+    /// blocks without such payment are allowed, e.g. false positives of BIP158 filters
+    fn blocks(&mut self, scripts: &[Script]) -> Result<Vec<Block>, Error>;
+}
+
+impl BlockSource for Vec<Block> {
+    fn blocks(&mut self, scripts: &[Script]) -> Result<Vec<Block>, Error> {
+        let scripts = scripts.iter().collect::<HashSet<_>>();
+        Ok(self
+            .iter()
+            .filter(|b| {
+                b.txdata
+                    .iter()
+                    .any(|t| t.output.iter().any(|o| scripts.contains(&o.script_pubkey)))
+            })
+            .cloned()
+            .collect())
+    }
+}
+
+/// discover used accounts of the address types and the coins they hold
+/// accounts of each type are scanned from 0 until the first unused one
+/// both the receiving (0) and change (1) sub-account are kept for a used account
+/// an account number can only be used with one address type in a MasterAccount,
+/// discovery fails if it finds the same number used with several types,
+/// discover each of them into a separate MasterAccount then
+pub fn discover<S: BlockSource>(
+    mut master: MasterAccount,
+    unlocker: &mut Unlocker,
+    source: &mut S,
+    address_types: &[AccountAddressType],
+    gap_limit: u32,
+) -> Result<(MasterAccount, Coins), Error> {
+    for address_type in address_types {
+        for account_number in 0.. {
+            let mut scan = MasterAccount::watch_only(*master.master_public(), master.birth());
+            for sub in 0..2 {
+                scan.add_account(Account::new(
+                    unlocker,
+                    *address_type,
+                    account_number,
+                    sub,
+                    gap_limit,
+                )?);
+            }
+            // used keys extend the look ahead, whose scripts might be in blocks not yet seen
+            let mut known = 0;
+            loop {
+                let scripts = scan.get_scripts().map(|(s, _)| s).collect::<Vec<_>>();
+                if scripts.len() == known {
+                    break;
+                }
+                known = scripts.len();
+                let mut coins = Coins::new();
+                for block in source.blocks(scripts.as_slice())? {
+                    coins.process(&mut scan, &block);
+                }
+            }
+            if scan.accounts().values().all(|a| a.used() == 0) {
+                break;
+            }
+            for sub in 0..2 {
+                if master.get((account_number, sub)).is_some() {
+                    return Err(Error::Unsupported(
+                        "account number used with several address types, discover them separately",
+                    ));
+                }
+                master.add_account(
+                    scan.remove_account((account_number, sub))
+                        .expect("added above"),
+                );
+            }
+        }
+    }
+    let scripts = master.get_scripts().map(|(s, _)| s).collect::<Vec<_>>();
+    let mut coins = Coins::new();
+    for block in source.blocks(scripts.as_slice())? {
+        coins.process(&mut master, &block);
+    }
+    Ok((master, coins))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        blockdata::constants::genesis_block, network::constants::Network, util::hash::MerkleRoot,
+        BitcoinHash, BlockHeader, OutPoint, Transaction, TxIn, TxOut,
+    };
+    use bitcoin_hashes::sha256d;
+
+    use account::MasterKeyEntropy;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    fn block(prev: &Block, outputs: Vec<Script>) -> Block {
+        let tx = |lock_time| Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: outputs
+                .iter()
+                .map(|s| TxOut {
+                    script_pubkey: s.clone(),
+                    value: 1000,
+                })
+                .collect(),
+            lock_time,
+            version: 2,
+        };
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                time: 0,
+                nonce: 0,
+                bits: 0x1d00ffff,
+                prev_blockhash: prev.bitcoin_hash(),
+                merkle_root: sha256d::Hash::default(),
+            },
+            // the first transaction is processed as coinbase
+            txdata: vec![tx(1), tx(0)],
+        };
+        block.header.merkle_root = block.merkle_root();
+        block
+    }
+
+    #[test]
+    fn bip44_discovery() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let mut script = |t, account, sub, kix| {
+            Account::new(&mut unlocker, t, account, sub, kix + 1)
+                .unwrap()
+                .get_key(kix)
+                .unwrap()
+                .address
+                .script_pubkey()
+        };
+        let genesis = genesis_block(Network::Testnet);
+        let first = block(
+            &genesis,
+            vec![
+                script(AccountAddressType::P2WPKH, 0, 0, 0),
+                // within the gap of the key above
+                script(AccountAddressType::P2WPKH, 0, 0, 15),
+                script(AccountAddressType::P2WPKH, 1, 1, 3),
+                // beyond the first unused account 2
+                script(AccountAddressType::P2WPKH, 3, 0, 0),
+            ],
+        );
+        let second = block(
+            &first,
+            vec![
+                // only within the gap of the key in the previous block
+                script(AccountAddressType::P2WPKH, 0, 0, 30),
+                // beyond the gap
+                script(AccountAddressType::P2WPKH, 0, 1, 25),
+            ],
+        );
+        let mut blocks = vec![first, second];
+
+        let (master, coins) = discover(
+            master,
+            &mut unlocker,
+            &mut blocks,
+            &DISCOVERY_ADDRESS_TYPES,
+            GAP_LIMIT,
+        )
+        .unwrap();
+        assert_eq!(master.accounts().len(), 4);
+        assert_eq!(master.get((0, 0)).unwrap().used(), 31);
+        assert_eq!(master.get((0, 1)).unwrap().used(), 0);
+        assert_eq!(master.get((1, 0)).unwrap().used(), 0);
+        assert_eq!(master.get((1, 1)).unwrap().used(), 4);
+        assert!(master
+            .accounts()
+            .values()
+            .all(|a| a.address_type() == AccountAddressType::P2WPKH));
+        // two transactions paying to each of four discovered keys
+        assert_eq!(coins.confirmed_balance(), 8000);
+    }
+
+    #[test]
+    fn ambiguous_account_number() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let master_encrypted = master.encrypted().clone();
+        let master_public = *master.master_public();
+        let mut blocks = vec![block(
+            &genesis_block(Network::Testnet),
+            [AccountAddressType::P2PKH, AccountAddressType::P2WPKH]
+                .iter()
+                .map(|t| {
+                    Account::new(&mut unlocker, *t, 0, 0, 1)
+                        .unwrap()
+                        .get_key(0)
+                        .unwrap()
+                        .address
+                        .script_pubkey()
+                })
+                .collect(),
+        )];
+        match discover(
+            master,
+            &mut unlocker,
+            &mut blocks,
+            &DISCOVERY_ADDRESS_TYPES,
+            GAP_LIMIT,
+        ) {
+            Err(Error::Unsupported(
+                "account number used with several address types, discover them separately",
+            )) => {}
+            _ => panic!("accounts of the same number not refused"),
+        }
+        // each type on its own is restored at its number
+        for address_type in [AccountAddressType::P2PKH, AccountAddressType::P2WPKH].iter() {
+            let master =
+                MasterAccount::from_encrypted(master_encrypted.as_slice(), master_public, 0);
+            let (master, coins) = discover(
+                master,
+                &mut unlocker,
+                &mut blocks,
+                &[*address_type],
+                GAP_LIMIT,
+            )
+            .unwrap();
+            assert_eq!(master.get((0, 0)).unwrap().address_type(), *address_type);
+            assert_eq!(master.get((0, 0)).unwrap().account_number(), 0);
+            assert_eq!(coins.confirmed_balance(), 2000);
+        }
+    }
+}
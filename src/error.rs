@@ -21,7 +21,9 @@
 
 use std::{convert, error, fmt, io};
 
-use bitcoin::util::bip32;
+use bitcoin::bech32;
+use bitcoin::consensus::encode;
+use bitcoin::util::{base58, bip32, psbt};
 use crypto::symmetriccipher;
 
 /// An error class to offer a unified error interface upstream
@@ -30,6 +32,20 @@ pub enum Error {
     Unsupported(&'static str),
     /// mnemonic related error
     Mnemonic(&'static str),
+    /// output script descriptor related error
+    Descriptor(&'static str),
+    /// use outside of the limits of an unlocker session
+    Session(&'static str),
+    /// message signature related error
+    Message(&'static str),
+    /// BIP329 label related error
+    Label(&'static str),
+    /// pay-to-contract commitment does not verify
+    Contract(&'static str),
+    /// BIP47 payment code related error
+    PaymentCode(&'static str),
+    /// signing with a watch-only account
+    WatchOnly,
     /// wrong passphrase
     Passphrase,
     /// wrong network
@@ -38,26 +54,48 @@ pub enum Error {
     IO(io::Error),
     /// key derivation error
     KeyDerivation(bip32::Error),
+    /// extended key serialization error
+    Base58(base58::Error),
+    /// bech32 address encoding error
+    Bech32(bech32::Error),
     /// sekp256k1 error
     SecpError(secp256k1::Error),
     /// cipher error
     SymmetricCipherError(symmetriccipher::SymmetricCipherError),
+    /// BIP174 partially signed transaction error
+    PSBT(psbt::Error),
+    /// consensus encoding error
+    Encode(encode::Error),
+    /// JSON serialization error
+    Json(serde_json::Error),
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
+            Error::WatchOnly => "watch-only account can not sign",
             Error::Passphrase => "wrong passphrase",
             Error::Network => "wrong network",
             Error::Unsupported(s) => s,
             Error::Mnemonic(s) => s,
+            Error::Descriptor(s) => s,
+            Error::Session(s) => s,
+            Error::Message(s) => s,
+            Error::Label(s) => s,
+            Error::Contract(s) => s,
+            Error::PaymentCode(s) => s,
             Error::IO(ref err) => err.description(),
             Error::KeyDerivation(ref err) => err.description(),
+            Error::Base58(ref err) => err.description(),
+            Error::Bech32(ref err) => err.description(),
             Error::SecpError(ref err) => err.description(),
             Error::SymmetricCipherError(ref err) => match err {
                 &symmetriccipher::SymmetricCipherError::InvalidLength => "invalid length",
                 &symmetriccipher::SymmetricCipherError::InvalidPadding => "invalid padding",
             },
+            Error::PSBT(ref err) => err.description(),
+            Error::Encode(ref err) => err.description(),
+            Error::Json(ref err) => err.description(),
         }
     }
 
@@ -65,12 +103,24 @@ impl error::Error for Error {
         match *self {
             Error::Network => None,
             Error::Passphrase => None,
+            Error::WatchOnly => None,
             Error::Unsupported(_) => None,
             Error::Mnemonic(_) => None,
+            Error::Descriptor(_) => None,
+            Error::Session(_) => None,
+            Error::Message(_) => None,
+            Error::Label(_) => None,
+            Error::Contract(_) => None,
+            Error::PaymentCode(_) => None,
             Error::IO(ref err) => Some(err),
             Error::KeyDerivation(ref err) => Some(err),
+            Error::Base58(ref err) => Some(err),
+            Error::Bech32(ref err) => Some(err),
             Error::SecpError(ref err) => Some(err),
             Error::SymmetricCipherError(_) => None,
+            Error::PSBT(ref err) => Some(err),
+            Error::Encode(ref err) => Some(err),
+            Error::Json(ref err) => Some(err),
         }
     }
 }
@@ -81,11 +131,20 @@ impl fmt::Display for Error {
             // Both underlying errors already impl `Display`, so we defer to
             // their implementations.
             Error::Passphrase => write!(f, "wrong passphrase"),
+            Error::WatchOnly => write!(f, "watch-only account can not sign"),
             Error::Network => write!(f, "wrong network"),
             Error::Unsupported(ref s) => write!(f, "Unsupported: {}", s),
             Error::Mnemonic(ref s) => write!(f, "Mnemonic: {}", s),
+            Error::Descriptor(ref s) => write!(f, "Descriptor: {}", s),
+            Error::Session(ref s) => write!(f, "Session: {}", s),
+            Error::Message(ref s) => write!(f, "Message: {}", s),
+            Error::Label(ref s) => write!(f, "Label: {}", s),
+            Error::Contract(ref s) => write!(f, "Contract: {}", s),
+            Error::PaymentCode(ref s) => write!(f, "Payment code: {}", s),
             Error::IO(ref err) => write!(f, "IO error: {}", err),
             Error::KeyDerivation(ref err) => write!(f, "BIP32 error: {}", err),
+            Error::Base58(ref err) => write!(f, "Base58 error: {}", err),
+            Error::Bech32(ref err) => write!(f, "Bech32 error: {}", err),
             Error::SecpError(ref err) => write!(f, "Secp256k1 error: {}", err),
             Error::SymmetricCipherError(ref err) => write!(
                 f,
@@ -95,6 +154,9 @@ impl fmt::Display for Error {
                     &symmetriccipher::SymmetricCipherError::InvalidPadding => "invalid padding",
                 }
             ),
+            Error::PSBT(ref err) => write!(f, "PSBT error: {}", err),
+            Error::Encode(ref err) => write!(f, "Encode error: {}", err),
+            Error::Json(ref err) => write!(f, "JSON error: {}", err),
         }
     }
 }
@@ -129,6 +191,18 @@ impl convert::From<bip32::Error> for Error {
     }
 }
 
+impl convert::From<base58::Error> for Error {
+    fn from(err: base58::Error) -> Error {
+        Error::Base58(err)
+    }
+}
+
+impl convert::From<bech32::Error> for Error {
+    fn from(err: bech32::Error) -> Error {
+        Error::Bech32(err)
+    }
+}
+
 impl convert::From<symmetriccipher::SymmetricCipherError> for Error {
     fn from(err: symmetriccipher::SymmetricCipherError) -> Error {
         Error::SymmetricCipherError(err)
@@ -140,3 +214,21 @@ impl convert::From<secp256k1::Error> for Error {
         Error::SecpError(err)
     }
 }
+
+impl convert::From<psbt::Error> for Error {
+    fn from(err: psbt::Error) -> Error {
+        Error::PSBT(err)
+    }
+}
+
+impl convert::From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Error {
+        Error::Encode(err)
+    }
+}
+
+impl convert::From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
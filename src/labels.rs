@@ -0,0 +1,346 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Labels
+//!
+//! User labels of transactions, addresses, outpoints and extended keys
+//! with import and export of the BIP329 JSON lines format
+//!
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use bitcoin::{util::bip32::ExtendedPubKey, Address, OutPoint, PublicKey};
+use bitcoin_hashes::{hex::FromHex, sha256d};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use account::{InstantiatedKey, STORAGE_VERSION};
+use error::Error;
+use taproot;
+
+/// what a label is attached to, the types of BIP329
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum LabelRef {
+    /// a transaction by its id
+    Tx(sha256d::Hash),
+    /// an address in its string form
+    Address(String),
+    /// a public key in hex
+    PublicKey(String),
+    /// an input spending the outpoint
+    Input(OutPoint),
+    /// an output
+    Output(OutPoint),
+    /// an extended public key in its string form
+    Xpub(String),
+}
+
+impl LabelRef {
+    /// the label reference of an address, bech32m for segwit version 1 and above
+    pub fn address(address: &Address) -> LabelRef {
+        LabelRef::Address(taproot::encode_address(address))
+    }
+
+    /// the label reference of the address of a key
+    pub fn key(key: &InstantiatedKey) -> LabelRef {
+        LabelRef::address(&key.address)
+    }
+
+    pub fn public_key(key: &PublicKey) -> LabelRef {
+        LabelRef::PublicKey(key.to_string())
+    }
+
+    pub fn xpub(key: &ExtendedPubKey) -> LabelRef {
+        LabelRef::Xpub(key.to_string())
+    }
+
+    fn from_record(kind: &str, reference: &str) -> Result<LabelRef, Error> {
+        let outpoint = || {
+            OutPoint::from_str(reference).map_err(|_| Error::Label("invalid outpoint reference"))
+        };
+        Ok(match kind {
+            "tx" => LabelRef::Tx(
+                sha256d::Hash::from_hex(reference)
+                    .map_err(|_| Error::Label("invalid transaction reference"))?,
+            ),
+            "addr" => LabelRef::Address(reference.to_string()),
+            "pubkey" => LabelRef::PublicKey(reference.to_string()),
+            "input" => LabelRef::Input(outpoint()?),
+            "output" => LabelRef::Output(outpoint()?),
+            "xpub" => LabelRef::Xpub(reference.to_string()),
+            _ => return Err(Error::Label("unknown label type")),
+        })
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            LabelRef::Tx(_) => "tx",
+            LabelRef::Address(_) => "addr",
+            LabelRef::PublicKey(_) => "pubkey",
+            LabelRef::Input(_) => "input",
+            LabelRef::Output(_) => "output",
+            LabelRef::Xpub(_) => "xpub",
+        }
+    }
+
+    fn reference(&self) -> String {
+        match self {
+            LabelRef::Tx(txid) => txid.to_string(),
+            LabelRef::Input(point) | LabelRef::Output(point) => point.to_string(),
+            LabelRef::Address(s) | LabelRef::PublicKey(s) | LabelRef::Xpub(s) => s.clone(),
+        }
+    }
+}
+
+/// a label
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Label {
+    pub label: String,
+    /// key origin of the wallet as a descriptor fragment, e.g. wpkh([d34db33f/84'/0'/0'])
+    pub origin: Option<String>,
+    /// if an output can be spent, only for outputs
+    pub spendable: Option<bool>,
+}
+
+/// a BIP329 record, also the storage format of a label
+/// fields of other BIP329 extensions are ignored
+#[derive(Serialize, Deserialize)]
+struct Record {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(rename = "ref")]
+    reference: String,
+    #[serde(default)]
+    label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spendable: Option<bool>,
+}
+
+/// Label store
+#[derive(Default, Eq, PartialEq, Debug)]
+pub struct Labels {
+    labels: HashMap<LabelRef, Label>,
+}
+
+/// storage format of the labels
+#[derive(Serialize, Deserialize)]
+struct StoredLabels {
+    version: u32,
+    labels: Vec<Record>,
+}
+
+impl Serialize for Labels {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        StoredLabels {
+            version: STORAGE_VERSION,
+            labels: self.records(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Labels {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Labels, D::Error> {
+        let stored = StoredLabels::deserialize(deserializer)?;
+        if stored.version != STORAGE_VERSION {
+            return Err(de::Error::custom(format!(
+                "unknown labels storage version {}",
+                stored.version
+            )));
+        }
+        let mut labels = Labels::new();
+        for record in stored.labels {
+            labels.add_record(record).map_err(de::Error::custom)?;
+        }
+        Ok(labels)
+    }
+}
+
+impl Labels {
+    pub fn new() -> Labels {
+        Labels {
+            labels: HashMap::new(),
+        }
+    }
+
+    /// set the label of a reference, returns the previous label
+    pub fn set(&mut self, reference: LabelRef, label: Label) -> Option<Label> {
+        self.labels.insert(reference, label)
+    }
+
+    pub fn get(&self, reference: &LabelRef) -> Option<&Label> {
+        self.labels.get(reference)
+    }
+
+    pub fn remove(&mut self, reference: &LabelRef) -> Option<Label> {
+        self.labels.remove(reference)
+    }
+
+    pub fn labels(&self) -> &HashMap<LabelRef, Label> {
+        &self.labels
+    }
+
+    /// import BIP329 JSON lines, labels of the same reference are replaced
+    /// returns the number of labels imported
+    pub fn import_bip329(&mut self, jsonl: &str) -> Result<usize, Error> {
+        let mut records = Vec::new();
+        for line in jsonl.lines().filter(|l| !l.trim().is_empty()) {
+            records.push(serde_json::from_str::<Record>(line)?);
+        }
+        // validate all before changing any
+        let mut imported = Vec::new();
+        for record in records {
+            let reference = LabelRef::from_record(&record.kind, &record.reference)?;
+            imported.push((reference, record));
+        }
+        let n = imported.len();
+        for (reference, record) in imported {
+            self.labels.insert(reference, label(record));
+        }
+        Ok(n)
+    }
+
+    /// export BIP329 JSON lines in the order of type and reference
+    pub fn export_bip329(&self) -> String {
+        self.records()
+            .iter()
+            .map(|r| serde_json::to_string(r).expect("serializable record") + "\n")
+            .collect()
+    }
+
+    fn records(&self) -> Vec<Record> {
+        let mut records = self
+            .labels
+            .iter()
+            .map(|(reference, label)| Record {
+                kind: reference.kind().to_string(),
+                reference: reference.reference(),
+                label: label.label.clone(),
+                origin: label.origin.clone(),
+                spendable: label.spendable,
+            })
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| (&a.kind, &a.reference).cmp(&(&b.kind, &b.reference)));
+        records
+    }
+
+    fn add_record(&mut self, record: Record) -> Result<(), Error> {
+        let reference = LabelRef::from_record(&record.kind, &record.reference)?;
+        self.labels.insert(reference, label(record));
+        Ok(())
+    }
+}
+
+fn label(record: Record) -> Label {
+    Label {
+        label: record.label,
+        origin: record.origin,
+        spendable: record.spendable,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::network::constants::Network;
+
+    use account::{Account, AccountAddressType, MasterAccount, MasterKeyEntropy, Unlocker};
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    const BIP329: &str = r#"{ "type": "tx", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd", "label": "Transaction", "origin": "wpkh([d34db33f/84'/0'/0'])" }
+{ "type": "addr", "ref": "bc1q34aq5drpuwy3wgl9lhup9892qp6svr8ldzyy7c", "label": "Address" }
+{ "type": "pubkey", "ref": "0283409659355b6d1cc3c32decd5d561abaac86c37a353b52895a5e6c196d6f448", "label": "Public Key" }
+{ "type": "input", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:0", "label": "Input" }
+{ "type": "output", "ref": "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1", "label": "Output", "spendable": false }
+{ "type": "xpub", "ref": "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8", "label": "Extended Public Key" }
+{ "type": "tx", "ref": "f546156d9044844e02b181026a1a407abfca62e7ea1159f87bbeaa77b4286c74", "label": "Account #1 Transaction", "origin": "wpkh([d34db33f/84'/0'/1'])" }
+"#;
+
+    #[test]
+    fn bip329() {
+        let mut labels = Labels::new();
+        assert_eq!(labels.import_bip329(BIP329).unwrap(), 7);
+        let point = OutPoint::from_str(
+            "f91d0a8a78462bc59398f2c5d7a84fcff491c26ba54c4833478b202796c8aafd:1",
+        )
+        .unwrap();
+        assert_eq!(
+            labels.get(&LabelRef::Output(point)),
+            Some(&Label {
+                label: "Output".to_string(),
+                origin: None,
+                spendable: Some(false),
+            })
+        );
+        assert!(labels.get(&LabelRef::Input(point)).is_none());
+
+        let exported = labels.export_bip329();
+        assert_eq!(exported.lines().count(), 7);
+        let mut reimported = Labels::new();
+        reimported.import_bip329(&exported).unwrap();
+        assert_eq!(reimported, labels);
+
+        // nothing is imported from an invalid file
+        let invalid = BIP329.replace(":0\"", ":x\"");
+        assert!(reimported.import_bip329(&invalid).is_err());
+        assert!(reimported
+            .import_bip329(r#"{ "type": "tx", "ref": "f00", "label": "" }"#)
+            .is_err());
+        assert_eq!(reimported, labels);
+    }
+
+    #[test]
+    fn store() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master
+            .add_account(Account::new(&mut unlocker, AccountAddressType::P2TR, 0, 0, 10).unwrap());
+        let key = master.get_mut((0, 0)).unwrap().next_key().unwrap().clone();
+        let mut labels = Labels::new();
+        labels.set(
+            LabelRef::key(&key),
+            Label {
+                label: "donations".to_string(),
+                ..Default::default()
+            },
+        );
+        labels.set(
+            LabelRef::xpub(master.master_public()),
+            Label {
+                label: "cold storage".to_string(),
+                ..Default::default()
+            },
+        );
+        let exported = labels.export_bip329();
+        assert!(exported.contains(&taproot::encode_address(&key.address)));
+
+        let stored = serde_json::to_string(&labels).unwrap();
+        let restored: Labels = serde_json::from_str(&stored).unwrap();
+        assert_eq!(restored, labels);
+        assert_eq!(
+            restored
+                .get(&LabelRef::address(&key.address))
+                .unwrap()
+                .label,
+            "donations"
+        );
+    }
+}
@@ -21,6 +21,7 @@
 #![deny(unused_must_use)]
 #![forbid(unsafe_code)]
 
+extern crate base64;
 extern crate bitcoin;
 extern crate bitcoin_hashes;
 extern crate crypto;
@@ -31,13 +32,25 @@ extern crate secp256k1;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
-#[cfg(test)]
 extern crate serde_json;
 
 pub mod account;
+pub mod bip47;
 pub mod coins;
 pub mod context;
+pub mod contract;
+pub mod descriptor;
+pub mod discovery;
 pub mod error;
+pub mod labels;
+pub mod message;
 pub mod mnemonic;
 pub mod proved;
+pub mod psbt;
+pub mod satisfier;
+pub mod slip132;
+pub mod signer;
 pub mod sss;
+pub mod sweep;
+pub mod taproot;
+pub mod template;
@@ -0,0 +1,521 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Signed messages
+//!
+//! Proof of address ownership with BIP137 compact signatures
+//! and BIP322 simple signatures, both base64 encoded
+//!
+
+use bitcoin::{
+    blockdata::{opcodes::all, script::Builder, transaction::SigHashType},
+    consensus::encode::{deserialize, serialize},
+    util::{address::Payload, bip143, misc::signed_msg_hash},
+    Address, OutPoint, PublicKey, Script, Transaction, TxIn, TxOut,
+};
+use bitcoin_hashes::{hash160, sha256, sha256d, Hash};
+use secp256k1::Signature;
+
+use account::{
+    bip143_sighash, Account, AccountAddressType, InstantiatedKey, KeyDerivation, MasterAccount,
+};
+use context::SecpContext;
+use error::Error;
+use signer::Signer;
+use taproot;
+
+impl MasterAccount {
+    /// BIP137 signature of a message with the key of a P2PKH, P2SHWPKH or P2WPKH account
+    pub fn sign_message_bip137<S>(
+        &self,
+        derivation: &KeyDerivation,
+        message: &str,
+        signer: &mut S,
+    ) -> Result<String, Error>
+    where
+        S: Signer + ?Sized,
+    {
+        let (account, instantiated) = self.message_key(derivation, signer)?;
+        let compressed = instantiated.public.compressed;
+        let header = match account.address_type() {
+            // uncompressed keys, e.g. imported ones, only have P2PKH addresses
+            AccountAddressType::P2PKH if !compressed => 27,
+            AccountAddressType::P2PKH => 31,
+            AccountAddressType::P2SHWPKH | AccountAddressType::P2WPKH if !compressed => {
+                return Err(Error::Message(
+                    "BIP137 signatures of segwit keys need a compressed key",
+                ))
+            }
+            AccountAddressType::P2SHWPKH => 35,
+            AccountAddressType::P2WPKH => 39,
+            _ => {
+                return Err(Error::Message(
+                    "BIP137 signatures are for P2PKH, P2SHWPKH and P2WPKH keys",
+                ))
+            }
+        };
+        let digest = signed_msg_hash(message);
+        let spent = TxOut {
+            script_pubkey: instantiated.address.script_pubkey(),
+            value: 0,
+        };
+        let signature =
+            account.signature(signer, &digest[..], derivation.kix, instantiated, &spent)?;
+        let compact = Signature::from_der(signature.as_slice())?.serialize_compact();
+        let context = SecpContext::new();
+        let recovery_id = (0..4)
+            .find(|id| {
+                context
+                    .recover(&digest[..], &compact[..], *id, compressed)
+                    .map(|k| k == instantiated.public)
+                    .unwrap_or(false)
+            })
+            .ok_or(Error::Message("signature can not be recovered"))?;
+        let mut data = vec![header + recovery_id];
+        data.extend_from_slice(&compact[..]);
+        Ok(base64::encode(&data))
+    }
+
+    /// BIP322 simple signature of a message with the key of a P2WPKH or P2TR account,
+    /// or of a P2WSH account whose script is <public key> OP_CHECKSIG
+    pub fn sign_message_bip322<S>(
+        &self,
+        derivation: &KeyDerivation,
+        message: &str,
+        signer: &mut S,
+    ) -> Result<String, Error>
+    where
+        S: Signer + ?Sized,
+    {
+        let (account, instantiated) = self.message_key(derivation, signer)?;
+        let to_spend = to_spend(&instantiated.address, message);
+        let spent = &to_spend.output[0];
+        let to_sign = to_sign(&to_spend, Vec::new());
+        let witness = match account.address_type() {
+            AccountAddressType::P2WPKH | AccountAddressType::P2WSH(_) => {
+                let (script_code, last) = match account.address_type() {
+                    AccountAddressType::P2WPKH => (
+                        instantiated.script_code.clone(),
+                        instantiated.public.to_bytes(),
+                    ),
+                    _ => {
+                        if single_key(&instantiated.script_code) != Some(instantiated.public) {
+                            return Err(Error::Message(
+                                "BIP322 simple signatures need a <public key> OP_CHECKSIG script",
+                            ));
+                        }
+                        (
+                            instantiated.script_code.clone(),
+                            instantiated.script_code.to_bytes(),
+                        )
+                    }
+                };
+                let sighash = bip143_sighash(
+                    &bip143::SighashComponents::new(&to_sign),
+                    &to_sign,
+                    0,
+                    &script_code,
+                    0,
+                    SigHashType::All,
+                );
+                let mut signature =
+                    account.signature(signer, &sighash[..], derivation.kix, instantiated, spent)?;
+                signature.push(SigHashType::All.as_u32() as u8);
+                vec![signature, last]
+            }
+            AccountAddressType::P2TR => {
+                let sighash = taproot::sighash(&to_sign, 0, std::slice::from_ref(spent), 0)?;
+                vec![account.signature(
+                    signer,
+                    &sighash[..],
+                    derivation.kix,
+                    instantiated,
+                    spent,
+                )?]
+            }
+            _ => {
+                return Err(Error::Message(
+                    "BIP322 simple signatures are for native segwit keys",
+                ))
+            }
+        };
+        Ok(base64::encode(serialize(&witness)))
+    }
+
+    fn message_key<S>(
+        &self,
+        derivation: &KeyDerivation,
+        signer: &S,
+    ) -> Result<(&Account, &InstantiatedKey), Error>
+    where
+        S: Signer + ?Sized,
+    {
+        let account = self
+            .get((derivation.account, derivation.sub))
+            .ok_or(Error::Message("no such account"))?;
+        if account.is_watch_only() && signer.derives_from_seed() {
+            return Err(Error::WatchOnly);
+        }
+        let instantiated = account
+            .get_key(derivation.kix)
+            .ok_or(Error::Message("no such key"))?;
+        Ok((account, instantiated))
+    }
+}
+
+/// verify a BIP137 signature of a message for a P2PKH, P2SHWPKH or P2WPKH address
+/// signatures with the header of compressed P2PKH keys are accepted for all three,
+/// as made by wallets predating BIP137
+pub fn verify_message_bip137(
+    address: &Address,
+    message: &str,
+    signature: &str,
+) -> Result<(), Error> {
+    let data = base64::decode(signature).map_err(|_| Error::Message("invalid base64"))?;
+    if data.len() != 65 || data[0] < 27 || data[0] > 42 {
+        return Err(Error::Message("invalid BIP137 signature"));
+    }
+    let recovery_id = (data[0] - 27) & 3;
+    let flag = (data[0] - 27) >> 2;
+    let digest = signed_msg_hash(message);
+    let public = SecpContext::new().recover(&digest[..], &data[1..], recovery_id, flag > 0)?;
+    let network = address.network;
+    let matches = match flag {
+        0 => *address == Address::p2pkh(&public, network),
+        1 => {
+            *address == Address::p2pkh(&public, network)
+                || *address == Address::p2shwpkh(&public, network)
+                || *address == Address::p2wpkh(&public, network)
+        }
+        2 => *address == Address::p2shwpkh(&public, network),
+        _ => *address == Address::p2wpkh(&public, network),
+    };
+    if !matches {
+        return Err(Error::Message("signature is not of the address"));
+    }
+    Ok(())
+}
+
+/// verify a BIP322 simple signature of a message for a P2WPKH, P2TR
+/// or P2WSH address of a <public key> OP_CHECKSIG script
+pub fn verify_message_bip322(
+    address: &Address,
+    message: &str,
+    signature: &str,
+) -> Result<(), Error> {
+    let witness: Vec<Vec<u8>> = deserialize(
+        base64::decode(signature)
+            .map_err(|_| Error::Message("invalid base64"))?
+            .as_slice(),
+    )?;
+    let to_spend = to_spend(address, message);
+    let to_sign = to_sign(&to_spend, witness.clone());
+    let context = SecpContext::new();
+    let (version, program) = match address.payload {
+        Payload::WitnessProgram {
+            version,
+            ref program,
+        } => (version.to_u8(), program),
+        _ => {
+            return Err(Error::Message(
+                "BIP322 simple signatures are for native segwit addresses",
+            ))
+        }
+    };
+    match (version, program.len(), witness.len()) {
+        (0, 20, 2) | (0, 32, 2) => {
+            let (public, script_code) = if program.len() == 20 {
+                let public = PublicKey::from_slice(witness[1].as_slice())
+                    .map_err(|_| Error::Message("invalid public key"))?;
+                if !public.compressed || hash160::Hash::hash(&witness[1])[..] != program[..] {
+                    return Err(Error::Message("signature is not of the address"));
+                }
+                (public, p2wpkh_script_code(&witness[1]))
+            } else {
+                let script = Script::from(witness[1].clone());
+                if sha256::Hash::hash(&witness[1])[..] != program[..] {
+                    return Err(Error::Message("signature is not of the address"));
+                }
+                (
+                    single_key(&script).ok_or(Error::Message(
+                        "BIP322 simple signatures need a <public key> OP_CHECKSIG script",
+                    ))?,
+                    script,
+                )
+            };
+            let signature = &witness[0];
+            if signature.last() != Some(&(SigHashType::All.as_u32() as u8)) {
+                return Err(Error::Message("BIP322 signatures must be SIGHASH_ALL"));
+            }
+            let sighash = bip143_sighash(
+                &bip143::SighashComponents::new(&to_sign),
+                &to_sign,
+                0,
+                &script_code,
+                0,
+                SigHashType::All,
+            );
+            context.verify(
+                &sighash[..],
+                &Signature::from_der(&signature[..signature.len() - 1])?,
+                &public,
+            )
+        }
+        (1, 32, 1) => {
+            let signature = &witness[0];
+            let hash_type = match signature.len() {
+                64 => 0,
+                65 if signature[64] != 0 => signature[64],
+                _ => return Err(Error::Message("invalid schnorr signature")),
+            };
+            let sighash = taproot::sighash(&to_sign, 0, &to_spend.output, hash_type)?;
+            context.schnorr_verify(&sighash[..], &signature[..64], &program[..])
+        }
+        _ => Err(Error::Message(
+            "BIP322 simple signature does not fit the address",
+        )),
+    }
+}
+
+/// BIP322 tagged hash of a message
+pub fn message_hash(message: &str) -> sha256::Hash {
+    taproot::tagged_hash("BIP0322-signed-message", &[message.as_bytes()])
+}
+
+/// virtual transaction paying to the address, committing to the message
+fn to_spend(address: &Address, message: &str) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: sha256d::Hash::default(),
+                vout: 0xffff_ffff,
+            },
+            script_sig: Builder::new()
+                .push_int(0)
+                .push_slice(&message_hash(message)[..])
+                .into_script(),
+            sequence: 0,
+            witness: Vec::new(),
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: address.script_pubkey(),
+        }],
+    }
+}
+
+/// virtual transaction spending to_spend, whose witness is the signature
+fn to_sign(to_spend: &Transaction, witness: Vec<Vec<u8>>) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.txid(),
+                vout: 0,
+            },
+            script_sig: Script::new(),
+            sequence: 0,
+            witness,
+        }],
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: Builder::new().push_opcode(all::OP_RETURN).into_script(),
+        }],
+    }
+}
+
+fn p2wpkh_script_code(public: &[u8]) -> Script {
+    Builder::new()
+        .push_opcode(all::OP_DUP)
+        .push_opcode(all::OP_HASH160)
+        .push_slice(&hash160::Hash::hash(public)[..])
+        .push_opcode(all::OP_EQUALVERIFY)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+/// the key of a <public key> OP_CHECKSIG script
+fn single_key(script: &Script) -> Option<PublicKey> {
+    let bytes = script.as_bytes();
+    if bytes.len() == 35 && bytes[0] == 33 && bytes[34] == all::OP_CHECKSIG.into_u8() {
+        PublicKey::from_slice(&bytes[1..34]).ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::{network::constants::Network, util::bip32::ExtendedPrivKey, PrivateKey};
+
+    use account::{MasterKeyEntropy, Unlocker};
+    use signer::MockSigner;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn bip322_vectors() {
+        assert_eq!(
+            message_hash("").to_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            message_hash("Hello World").to_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+        let address = Address::from_str("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l").unwrap();
+        let signature = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+        verify_message_bip322(&address, "Hello World", signature).unwrap();
+        assert!(verify_message_bip322(&address, "", signature).is_err());
+    }
+
+    fn key(master: &MasterAccount, account: u32, kix: u32) -> (KeyDerivation, Address) {
+        let instantiated = master.get((account, 0)).unwrap().get_key(kix).unwrap();
+        (
+            KeyDerivation {
+                account,
+                sub: 0,
+                kix,
+                tweak: instantiated.tweak.clone(),
+                csv: instantiated.csv,
+                imported: false,
+                path: None,
+            },
+            instantiated.address.clone(),
+        )
+    }
+
+    #[test]
+    fn sign_verify() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        for (n, t) in [
+            AccountAddressType::P2PKH,
+            AccountAddressType::P2SHWPKH,
+            AccountAddressType::P2WPKH,
+            AccountAddressType::P2TR,
+            AccountAddressType::P2WSH(4711),
+        ]
+        .iter()
+        .enumerate()
+        {
+            // scripts of P2WSH accounts are added explicitly
+            let look_ahead = if n == 4 { 0 } else { 5 };
+            master.add_account(Account::new(&mut unlocker, *t, n as u32, 0, look_ahead).unwrap());
+        }
+        master
+            .get_mut((4, 0))
+            .unwrap()
+            .add_script_key(
+                |pk: &PublicKey, _| {
+                    Builder::new()
+                        .push_slice(pk.to_bytes().as_slice())
+                        .push_opcode(all::OP_CHECKSIG)
+                        .into_script()
+                },
+                None,
+                None,
+            )
+            .unwrap();
+        let message = "I own this address";
+
+        for account in 0..3 {
+            let (derivation, address) = key(&master, account, 2);
+            let signature = master
+                .sign_message_bip137(&derivation, message, &mut unlocker)
+                .unwrap();
+            verify_message_bip137(&address, message, &signature).unwrap();
+            assert!(verify_message_bip137(&address, "I don't", &signature).is_err());
+            let (_, other) = key(&master, account, 3);
+            assert!(verify_message_bip137(&other, message, &signature).is_err());
+        }
+        let (derivation, _) = key(&master, 3, 0);
+        assert!(master
+            .sign_message_bip137(&derivation, message, &mut unlocker)
+            .is_err());
+
+        for account in 2..5 {
+            let (derivation, address) = key(&master, account, 0);
+            let signature = master
+                .sign_message_bip322(&derivation, message, &mut unlocker)
+                .unwrap();
+            verify_message_bip322(&address, message, &signature).unwrap();
+            assert!(verify_message_bip322(&address, "I don't", &signature).is_err());
+            let (_, other) = key(&master, 2, 1);
+            assert!(verify_message_bip322(&other, message, &signature).is_err());
+        }
+        let (derivation, _) = key(&master, 1, 0);
+        assert!(master
+            .sign_message_bip322(&derivation, message, &mut unlocker)
+            .is_err());
+
+        // any signer of the key will do
+        let (derivation, address) = key(&master, 2, 0);
+        let mut device = MockSigner::new(*unlocker.master_private());
+        let signature = master
+            .sign_message_bip322(&derivation, message, &mut device)
+            .unwrap();
+        verify_message_bip322(&address, message, &signature).unwrap();
+        let other = ExtendedPrivKey::new_master(Network::Testnet, &[0x42; 32]).unwrap();
+        assert!(master
+            .sign_message_bip322(&derivation, message, &mut MockSigner::new(other))
+            .is_err());
+    }
+
+    #[test]
+    fn uncompressed_key() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let key = PrivateKey {
+            compressed: false,
+            network: Network::Testnet,
+            key: secp256k1::SecretKey::from_slice(&[0x42; 32]).unwrap(),
+        };
+        let public = unlocker.context().public_from_private(&key);
+        let message = "I own this address";
+        let mut imported =
+            Account::new_imported(&mut unlocker, AccountAddressType::P2PKH, 0, 0).unwrap();
+        imported.add_imported_private(&mut unlocker, &key).unwrap();
+        master.add_account(imported);
+
+        let address = Address::p2pkh(&public, Network::Testnet);
+        let derivation = master.get_derivation(&address.script_pubkey()).unwrap();
+        let signature = master
+            .sign_message_bip137(&derivation, message, &mut unlocker)
+            .unwrap();
+        assert!(base64::decode(&signature).unwrap()[0] < 31);
+        verify_message_bip137(&address, message, &signature).unwrap();
+        let compressed = PublicKey {
+            compressed: true,
+            key: public.key,
+        };
+        assert!(verify_message_bip137(
+            &Address::p2pkh(&compressed, Network::Testnet),
+            message,
+            &signature
+        )
+        .is_err());
+    }
+}
@@ -25,6 +25,7 @@ use crypto::{
     hmac::Hmac,
     pbkdf2::pbkdf2,
     sha2::{Sha256, Sha512},
+    util::secure_memset,
 };
 use error::Error;
 use std::io::Cursor;
@@ -43,11 +44,16 @@ impl Mnemonic {
     /// create a seed from mnemonic
     /// with optional passphrase for plausible deniability see BIP39
     pub fn to_seed(&self, pd_passphrase: Option<&str>) -> Seed {
-        let mut mac = Hmac::new(Sha512::new(), self.to_string().as_bytes());
+        let mut words = self.to_string().into_bytes();
+        let mut mac = Hmac::new(Sha512::new(), words.as_slice());
+        secure_memset(words.as_mut_slice(), 0);
         let mut output = [0u8; 64];
-        let passphrase = "mnemonic".to_owned() + pd_passphrase.unwrap_or("");
-        pbkdf2(&mut mac, passphrase.as_bytes(), 2048, &mut output);
-        Seed(output.to_vec())
+        let mut passphrase = ("mnemonic".to_owned() + pd_passphrase.unwrap_or("")).into_bytes();
+        pbkdf2(&mut mac, passphrase.as_slice(), 2048, &mut output);
+        secure_memset(passphrase.as_mut_slice(), 0);
+        let seed = Seed(output.to_vec());
+        secure_memset(&mut output, 0);
+        seed
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &str> {
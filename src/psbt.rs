@@ -0,0 +1,536 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Partially signed transactions
+//!
+//! BIP174 creation, signing, combination and finalization
+//!
+
+use bitcoin::{
+    blockdata::script::Builder,
+    blockdata::{
+        opcodes::all,
+        transaction::{SigHashType, TxOut},
+    },
+    util::bip143,
+    util::bip32::{DerivationPath, Fingerprint},
+    util::psbt::{Input, PartiallySignedTransaction},
+    OutPoint, PublicKey, Script, Transaction,
+};
+use bitcoin_hashes::{hash160, Hash};
+
+use account::{
+    bip143_sighash, parse_multisig_script, Account, AccountAddressType, InstantiatedKey,
+    MasterAccount,
+};
+use coins::Coins;
+use error::Error;
+use signer::Signer;
+
+impl MasterAccount {
+    /// create a partially signed transaction spending coins of this master account
+    /// inputs and outputs using our keys are annotated with previous outputs, scripts
+    /// and BIP32 derivation, other inputs and outputs are left empty
+    pub fn create_psbt(
+        &self,
+        transaction: Transaction,
+        coins: &Coins,
+    ) -> Result<PartiallySignedTransaction, Error> {
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(transaction)?;
+        let fingerprint = self.master_public().fingerprint();
+        let tx = psbt.global.unsigned_tx.clone();
+
+        for (input, txin) in psbt.inputs.iter_mut().zip(tx.input.iter()) {
+            let point = &txin.previous_output;
+            let coin = match coins
+                .confirmed()
+                .get(point)
+                .or_else(|| coins.unconfirmed().get(point))
+            {
+                Some(coin) => coin,
+                None => continue,
+            };
+            let d = &coin.derivation;
+            if let Some(account) = self.get((d.account, d.sub)) {
+                if let Some(instantiated) = account.get_key(d.kix) {
+                    match account.address_type() {
+                        AccountAddressType::P2PKH => {
+                            input.non_witness_utxo =
+                                coins.proofs().get(&point.txid).map(|p| p.get_transaction())
+                        }
+                        _ => input.witness_utxo = Some(coin.output.clone()),
+                    }
+                    let (redeem_script, witness_script) = scripts(account, instantiated);
+                    input.redeem_script = redeem_script;
+                    input.witness_script = witness_script;
+                    if let Some((pk, origin)) =
+                        key_origin(account, d.kix, instantiated, fingerprint)
+                    {
+                        input.hd_keypaths.insert(pk, origin);
+                    }
+                }
+            }
+        }
+
+        for (output, txout) in psbt.outputs.iter_mut().zip(tx.output.iter()) {
+            if let Some(d) = self.get_derivation(&txout.script_pubkey) {
+                if let Some(account) = self.get((d.account, d.sub)) {
+                    if let Some(instantiated) = account.get_key(d.kix) {
+                        let (redeem_script, witness_script) = scripts(account, instantiated);
+                        output.redeem_script = redeem_script;
+                        output.witness_script = witness_script;
+                        if let Some((pk, origin)) =
+                            key_origin(account, d.kix, instantiated, fingerprint)
+                        {
+                            output.hd_keypaths.insert(pk, origin);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(psbt)
+    }
+
+    /// add signatures of our keys to a partially signed transaction
+    /// the sighash type of an input is used if present, otherwise hash_type
+    /// returns the number of signatures added
+    pub fn sign_psbt<S>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        hash_type: SigHashType,
+        signer: &mut S,
+    ) -> Result<usize, Error>
+    where
+        S: Signer + ?Sized,
+    {
+        let mut n_signatures = 0;
+        for (_, a) in self.accounts().iter() {
+            n_signatures += a.sign_psbt(psbt, hash_type, signer)?;
+        }
+        Ok(n_signatures)
+    }
+}
+
+impl Account {
+    /// add signatures of keys in this account to a partially signed transaction
+    /// works for types except P2WSH and P2TR
+    pub fn sign_psbt<S>(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+        hash_type: SigHashType,
+        signer: &mut S,
+    ) -> Result<usize, Error>
+    where
+        S: Signer + ?Sized,
+    {
+        let mut signed = 0;
+        let txclone = psbt.global.unsigned_tx.clone();
+        let mut bip143hasher: Option<bip143::SighashComponents> = None;
+        for (ix, input) in psbt.inputs.iter_mut().enumerate() {
+            if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+                continue;
+            }
+            let spend = match spent_output(input, &txclone.input[ix].previous_output) {
+                Some(spend) => spend,
+                None => continue,
+            };
+            if let Some((kix, instantiated)) = self.get_key_by_script(&spend.script_pubkey) {
+                if self.is_watch_only() && signer.derives_from_seed() {
+                    return Err(Error::WatchOnly);
+                }
+                let hash_type = input.sighash_type.unwrap_or(hash_type);
+                let sighash = match self.address_type() {
+                    AccountAddressType::P2PKH => {
+                        txclone.signature_hash(ix, &spend.script_pubkey, hash_type.as_u32())
+                    }
+                    AccountAddressType::P2TR => {
+                        // BIP174 of this bitcoin version has no taproot fields
+                        return Err(Error::Unsupported("taproot inputs in PSBT"));
+                    }
+                    _ => {
+                        let hasher = bip143hasher
+                            .unwrap_or_else(|| bip143::SighashComponents::new(&txclone));
+                        let sighash = bip143_sighash(
+                            &hasher,
+                            &txclone,
+                            ix,
+                            &instantiated.script_code,
+                            spend.value,
+                            hash_type,
+                        );
+                        bip143hasher = Some(hasher);
+                        sighash
+                    }
+                };
+                let mut with_hashtype =
+                    self.signature(signer, &sighash[..], kix, instantiated, &spend)?;
+                with_hashtype.push(hash_type.as_u32() as u8);
+                input
+                    .partial_sigs
+                    .insert(instantiated.public, with_hashtype);
+                input.sighash_type = Some(hash_type);
+                let (redeem_script, witness_script) = scripts(self, instantiated);
+                if input.redeem_script.is_none() {
+                    input.redeem_script = redeem_script;
+                }
+                if input.witness_script.is_none() {
+                    input.witness_script = witness_script;
+                }
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+}
+
+/// combine partially signed versions of the same transaction
+pub fn combine(
+    psbts: Vec<PartiallySignedTransaction>,
+) -> Result<PartiallySignedTransaction, Error> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts
+        .next()
+        .ok_or(Error::Unsupported("nothing to combine"))?;
+    for psbt in psbts {
+        combined.merge(psbt)?;
+    }
+    Ok(combined)
+}
+
+/// finalize inputs that have the signatures needed to spend them
+/// the finalized transaction can be obtained with extract_tx
+/// returns the number of inputs finalized
+pub fn finalize(psbt: &mut PartiallySignedTransaction) -> Result<usize, Error> {
+    let mut finalized = 0;
+    let txclone = psbt.global.unsigned_tx.clone();
+    for (ix, input) in psbt.inputs.iter_mut().enumerate() {
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        let spend = match spent_output(input, &txclone.input[ix].previous_output) {
+            Some(spend) => spend,
+            None => continue,
+        };
+        let signature_of = |script: &Script, scripter: fn(&PublicKey) -> Script| {
+            input
+                .partial_sigs
+                .iter()
+                .find(|(pk, _)| scripter(pk) == *script)
+                .map(|(pk, sig)| (pk.to_bytes(), sig.clone()))
+        };
+        if spend.script_pubkey.is_p2pkh() {
+            if let Some((pk, sig)) = signature_of(&spend.script_pubkey, p2pkh) {
+                input.final_script_sig = Some(
+                    Builder::new()
+                        .push_slice(sig.as_slice())
+                        .push_slice(pk.as_slice())
+                        .into_script(),
+                );
+            }
+        } else if spend.script_pubkey.is_v0_p2wpkh() {
+            if let Some((pk, sig)) = signature_of(&spend.script_pubkey, v0_p2wpkh) {
+                input.final_script_sig = Some(Script::new());
+                input.final_script_witness = Some(vec![sig, pk]);
+            }
+        } else if spend.script_pubkey.is_p2sh() {
+            if let Some(ref redeem_script) = input.redeem_script {
+                if redeem_script.to_p2sh() == spend.script_pubkey && redeem_script.is_v0_p2wpkh() {
+                    if let Some((pk, sig)) = signature_of(redeem_script, v0_p2wpkh) {
+                        input.final_script_sig =
+                            Some(Builder::new().push_slice(&redeem_script[..]).into_script());
+                        input.final_script_witness = Some(vec![sig, pk]);
+                    }
+                } else if redeem_script.to_p2sh() == spend.script_pubkey
+                    && redeem_script.is_v0_p2wsh()
+                {
+                    if let Some(witness) = wsh_witness(input, redeem_script) {
+                        input.final_script_sig =
+                            Some(Builder::new().push_slice(&redeem_script[..]).into_script());
+                        input.final_script_witness = Some(witness);
+                    }
+                }
+            }
+        } else if spend.script_pubkey.is_v0_p2wsh() {
+            if let Some(witness) = wsh_witness(input, &spend.script_pubkey) {
+                input.final_script_sig = Some(Script::new());
+                input.final_script_witness = Some(witness);
+            }
+        }
+        if input.final_script_sig.is_some() {
+            input.partial_sigs.clear();
+            input.hd_keypaths.clear();
+            input.sighash_type = None;
+            input.redeem_script = None;
+            input.witness_script = None;
+            finalized += 1;
+        }
+    }
+    Ok(finalized)
+}
+
+/// witness satisfying the witness script of an input paid to the given v0 p2wsh script
+fn wsh_witness(input: &Input, wsh: &Script) -> Option<Vec<Vec<u8>>> {
+    let witness_script = input.witness_script.as_ref()?;
+    if witness_script.to_v0_p2wsh() != *wsh {
+        return None;
+    }
+    if let Some((threshold, keys)) = parse_multisig_script(witness_script) {
+        // signatures in the order of keys as CHECKMULTISIG expects
+        let sigs = keys
+            .iter()
+            .filter_map(|k| input.partial_sigs.get(k).cloned())
+            .take(threshold)
+            .collect::<Vec<_>>();
+        if sigs.len() < threshold {
+            return None;
+        }
+        let mut witness = vec![Vec::new()];
+        witness.extend(sigs);
+        witness.push(witness_script.to_bytes());
+        return Some(witness);
+    }
+    // otherwise only scripts spendable with <signature> <scriptCode>
+    if input.partial_sigs.len() == 1 {
+        let sig = input.partial_sigs.values().next().unwrap().clone();
+        return Some(vec![sig, witness_script.to_bytes()]);
+    }
+    None
+}
+
+/// the output spent by an input of a partially signed transaction
+fn spent_output(input: &Input, point: &OutPoint) -> Option<TxOut> {
+    if let Some(ref spend) = input.witness_utxo {
+        return Some(spend.clone());
+    }
+    if let Some(ref previous) = input.non_witness_utxo {
+        if previous.txid() == point.txid {
+            return previous.output.get(point.vout as usize).cloned();
+        }
+    }
+    None
+}
+
+/// redeem and witness script of an instantiated key
+fn scripts(account: &Account, instantiated: &InstantiatedKey) -> (Option<Script>, Option<Script>) {
+    match account.address_type() {
+        AccountAddressType::P2SHWPKH => (Some(v0_p2wpkh(&instantiated.public)), None),
+        AccountAddressType::P2WSH(_) => (None, Some(instantiated.script_code.clone())),
+        AccountAddressType::P2WSHMultisig => (None, Some(instantiated.script_code.clone())),
+        AccountAddressType::P2SHWSH(_) | AccountAddressType::P2SHWSHMultisig => (
+            Some(instantiated.script_code.to_v0_p2wsh()),
+            Some(instantiated.script_code.clone()),
+        ),
+        _ => (None, None),
+    }
+}
+
+/// BIP32 origin of an instantiated key, tweaked and imported keys have none
+fn key_origin(
+    account: &Account,
+    kix: u32,
+    instantiated: &InstantiatedKey,
+    fingerprint: Fingerprint,
+) -> Option<(PublicKey, (Fingerprint, DerivationPath))> {
+    if instantiated.tweak.is_some() || account.is_imported() {
+        return None;
+    }
+    Some((
+        instantiated.public,
+        (fingerprint, account.derivation_path(kix)),
+    ))
+}
+
+fn p2pkh(pk: &PublicKey) -> Script {
+    Builder::new()
+        .push_opcode(all::OP_DUP)
+        .push_opcode(all::OP_HASH160)
+        .push_slice(&hash160::Hash::hash(pk.to_bytes().as_slice())[..])
+        .push_opcode(all::OP_EQUALVERIFY)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+fn v0_p2wpkh(pk: &PublicKey) -> Script {
+    Builder::new()
+        .push_int(0)
+        .push_slice(&hash160::Hash::hash(pk.to_bytes().as_slice())[..])
+        .into_script()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::{
+        network::constants::Network, util::hash::MerkleRoot, BitcoinHash, Block, BlockHeader, TxIn,
+    };
+    use bitcoin_hashes::sha256d;
+
+    use account::{MasterKeyEntropy, Unlocker};
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const RBF: u32 = 0xffffffff - 2;
+
+    fn funded() -> (MasterAccount, Unlocker, Coins, Transaction) {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        for (n, t) in [
+            AccountAddressType::P2PKH,
+            AccountAddressType::P2SHWPKH,
+            AccountAddressType::P2WPKH,
+        ]
+        .iter()
+        .enumerate()
+        {
+            master.add_account(Account::new(&mut unlocker, *t, n as u32, 0, 10).unwrap());
+        }
+        let funding = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: (0..3)
+                .map(|n| TxOut {
+                    script_pubkey: master
+                        .get_mut((n, 0))
+                        .unwrap()
+                        .next_key()
+                        .unwrap()
+                        .address
+                        .script_pubkey(),
+                    value: 1000000,
+                })
+                .collect(),
+            lock_time: 0,
+            version: 2,
+        };
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                time: 0,
+                nonce: 0,
+                bits: 0x1d00ffff,
+                prev_blockhash: genesis_block(Network::Testnet).bitcoin_hash(),
+                merkle_root: sha256d::Hash::default(),
+            },
+            txdata: vec![funding.clone(), funding.clone()],
+        };
+        // the first transaction is processed as coinbase
+        block.txdata[0].lock_time = 1;
+        block.header.merkle_root = block.merkle_root();
+        let mut coins = Coins::new();
+        coins.process(&mut master, &block);
+        assert_eq!(coins.confirmed_balance(), 6000000);
+        let txid = funding.txid();
+        let spending = Transaction {
+            input: (0..3)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint { txid, vout },
+                    sequence: RBF,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                script_pubkey: master
+                    .get_mut((2, 0))
+                    .unwrap()
+                    .next_key()
+                    .unwrap()
+                    .address
+                    .script_pubkey(),
+                value: 2990000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        (master, unlocker, coins, spending)
+    }
+
+    fn verify(spending: &Transaction, coins: &Coins) {
+        let spent: HashMap<OutPoint, TxOut> = coins
+            .confirmed()
+            .iter()
+            .map(|(p, c)| (*p, c.output.clone()))
+            .collect();
+        spending.verify(|point| spent.get(point).cloned()).unwrap();
+    }
+
+    #[test]
+    fn test_psbt() {
+        let (master, mut unlocker, coins, spending) = funded();
+        let psbt = master.create_psbt(spending, &coins).unwrap();
+        assert!(psbt.inputs[0].non_witness_utxo.is_some());
+        assert!(psbt.inputs[1].witness_utxo.is_some());
+        assert!(psbt.inputs[1].redeem_script.is_some());
+        assert_eq!(psbt.outputs[0].hd_keypaths.len(), 1);
+        let (fingerprint, path) = psbt.inputs[2].hd_keypaths.values().next().unwrap();
+        assert_eq!(*fingerprint, master.master_public().fingerprint());
+        assert_eq!(path.to_string(), "m/84'/1'/2'/0/0");
+
+        // exchange as file
+        let mut psbt: PartiallySignedTransaction =
+            deserialize(serialize(&psbt).as_slice()).unwrap();
+        assert_eq!(
+            master
+                .sign_psbt(&mut psbt, SigHashType::All, &mut unlocker)
+                .unwrap(),
+            3
+        );
+        assert_eq!(finalize(&mut psbt).unwrap(), 3);
+        assert!(psbt.inputs.iter().all(|i| i.partial_sigs.is_empty()));
+        verify(&psbt.extract_tx(), &coins);
+    }
+
+    #[test]
+    fn test_psbt_combine() {
+        let (master, mut unlocker, coins, spending) = funded();
+        let psbt = master.create_psbt(spending, &coins).unwrap();
+        let mut legacy = psbt.clone();
+        let mut segwit = psbt.clone();
+        assert_eq!(
+            master
+                .get((0, 0))
+                .unwrap()
+                .sign_psbt(&mut legacy, SigHashType::All, &mut unlocker)
+                .unwrap(),
+            1
+        );
+        for n in 1..3 {
+            master
+                .get((n, 0))
+                .unwrap()
+                .sign_psbt(&mut segwit, SigHashType::All, &mut unlocker)
+                .unwrap();
+        }
+        let mut incomplete = legacy.clone();
+        assert_eq!(finalize(&mut incomplete).unwrap(), 1);
+
+        let mut combined = combine(vec![legacy, segwit]).unwrap();
+        assert_eq!(finalize(&mut combined).unwrap(), 3);
+        verify(&combined.extract_tx(), &coins);
+    }
+}
@@ -0,0 +1,264 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Witness satisfiers
+//!
+//! Witness stacks of P2WSH scripts that need more than a single signature:
+//! hash preimages, branch selection and signatures of further keys
+//!
+
+use std::collections::HashMap;
+
+use bitcoin::Script;
+
+use account::{AccountAddressType, KeyDerivation};
+
+/// an element of the witness stack satisfying a P2WSH script
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WitnessElement {
+    /// signature with the key the script was instantiated with
+    Signature,
+    /// signature with an other key of the master account, given by address type and derivation
+    KeySignature(AccountAddressType, KeyDerivation),
+    /// data pushed as is, e.g. a hash preimage
+    Push(Vec<u8>),
+    /// selects the OP_IF branch if true, the OP_ELSE branch otherwise
+    Branch(bool),
+}
+
+/// supplier of witness elements for spending P2WSH scripts
+pub trait Satisfier {
+    /// witness elements for spending the script of a key, without the script itself
+    /// in the order of the witness, the last element is the top of the stack the script starts with
+    /// None if the satisfier does not know the script, it is then spent with a single signature
+    fn satisfy(
+        &self,
+        script_code: &Script,
+        derivation: &KeyDerivation,
+    ) -> Option<Vec<WitnessElement>>;
+}
+
+/// witness elements by script
+impl Satisfier for HashMap<Script, Vec<WitnessElement>> {
+    fn satisfy(&self, script_code: &Script, _: &KeyDerivation) -> Option<Vec<WitnessElement>> {
+        self.get(script_code).cloned()
+    }
+}
+
+impl<F> Satisfier for F
+where
+    F: Fn(&Script, &KeyDerivation) -> Option<Vec<WitnessElement>>,
+{
+    fn satisfy(
+        &self,
+        script_code: &Script,
+        derivation: &KeyDerivation,
+    ) -> Option<Vec<WitnessElement>> {
+        self(script_code, derivation)
+    }
+}
+
+/// the witness of a single signature: <signature> <scriptCode>
+pub struct SingleSignature;
+
+impl Satisfier for SingleSignature {
+    fn satisfy(&self, _: &Script, _: &KeyDerivation) -> Option<Vec<WitnessElement>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::opcodes::all;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{
+        network::constants::Network, OutPoint, PublicKey, SigHashType, Transaction, TxIn, TxOut,
+    };
+    use bitcoin_hashes::{sha256, sha256d, Hash};
+
+    use account::{Account, MasterAccount, MasterKeyEntropy, Unlocker};
+    use error::Error;
+    use signer::Signer;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const RBF: u32 = 0xffffffff - 2;
+
+    #[test]
+    fn hashlock_or_multisig() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master
+            .add_account(Account::new(&mut unlocker, AccountAddressType::P2WPKH, 1, 0, 1).unwrap());
+        let other = master.get((1, 0)).unwrap().get_key(0).unwrap().clone();
+        let other_derivation = master
+            .get_derivation(&other.address.script_pubkey())
+            .unwrap();
+        let preimage = [42u8; 32];
+        let hash = sha256::Hash::hash(&preimage);
+        // IF <preimage of hash> and own key ELSE own and other key
+        let scripter = |pk: &PublicKey, _| {
+            Builder::new()
+                .push_opcode(all::OP_IF)
+                .push_opcode(all::OP_SHA256)
+                .push_slice(&hash[..])
+                .push_opcode(all::OP_EQUALVERIFY)
+                .push_slice(pk.to_bytes().as_slice())
+                .push_opcode(all::OP_CHECKSIG)
+                .push_opcode(all::OP_ELSE)
+                .push_int(2)
+                .push_slice(pk.to_bytes().as_slice())
+                .push_slice(other.public.to_bytes().as_slice())
+                .push_int(2)
+                .push_opcode(all::OP_CHECKMULTISIG)
+                .push_opcode(all::OP_ENDIF)
+                .into_script()
+        };
+        let mut account =
+            Account::new(&mut unlocker, AccountAddressType::P2WSH(4711), 0, 0, 0).unwrap();
+        account.add_script_key(scripter, None, None).unwrap();
+        let script_code = account.get_key(0).unwrap().script_code.clone();
+        master.add_account(account);
+
+        let funding = TxOut {
+            script_pubkey: script_code.to_v0_p2wsh(),
+            value: 100000,
+        };
+        let spending = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: RBF,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![funding.clone()],
+            lock_time: 0,
+            version: 2,
+        };
+        let resolver = |_: &OutPoint| Some(funding.clone());
+
+        let mut by_script = HashMap::new();
+        by_script.insert(
+            script_code.clone(),
+            vec![
+                WitnessElement::Signature,
+                WitnessElement::Push(preimage.to_vec()),
+                WitnessElement::Branch(true),
+            ],
+        );
+        let mut hashlock = spending.clone();
+        assert_eq!(
+            master
+                .sign_with_satisfier(
+                    &mut hashlock,
+                    SigHashType::All,
+                    &resolver,
+                    &mut unlocker,
+                    &by_script
+                )
+                .unwrap(),
+            1
+        );
+        assert_eq!(hashlock.input[0].witness.len(), 4);
+        hashlock.verify(|_| Some(funding.clone())).unwrap();
+
+        let both_keys = |_: &Script, derivation: &KeyDerivation| {
+            assert_eq!(derivation.account, 0);
+            Some(vec![
+                // dummy consumed by OP_CHECKMULTISIG
+                WitnessElement::Push(Vec::new()),
+                WitnessElement::Signature,
+                WitnessElement::KeySignature(AccountAddressType::P2WPKH, other_derivation.clone()),
+                WitnessElement::Branch(false),
+            ])
+        };
+        let mut multisig = spending.clone();
+        assert_eq!(
+            master
+                .sign_with_satisfier(
+                    &mut multisig,
+                    SigHashType::All,
+                    &resolver,
+                    &mut unlocker,
+                    &both_keys
+                )
+                .unwrap(),
+            2
+        );
+        multisig.verify(|_| Some(funding.clone())).unwrap();
+
+        // a single signature does not satisfy the script
+        let mut single = spending.clone();
+        master
+            .sign(&mut single, SigHashType::All, &resolver, &mut unlocker)
+            .unwrap();
+        assert!(single.verify(|_| Some(funding.clone())).is_err());
+
+        // signatures for other keys are checked before they are put into the witness
+        let mut faulty = spending.clone();
+        assert!(master
+            .sign_with_satisfier(
+                &mut faulty,
+                SigHashType::All,
+                &resolver,
+                &mut WrongKeySigner(&mut unlocker),
+                &both_keys
+            )
+            .is_err());
+
+        // an account alone does not know the keys of other accounts
+        let mut alone = spending.clone();
+        assert!(master
+            .get((0, 0))
+            .unwrap()
+            .sign_with_satisfier(
+                &mut alone,
+                SigHashType::All,
+                resolver,
+                &mut unlocker,
+                &both_keys
+            )
+            .is_err());
+    }
+
+    /// signs for keys of other accounts with the next key of their sub-account
+    struct WrongKeySigner<'a>(&'a mut Unlocker);
+
+    impl<'a> Signer for WrongKeySigner<'a> {
+        fn sign(
+            &mut self,
+            sighash: &[u8],
+            derivation: &KeyDerivation,
+            address_type: AccountAddressType,
+            spent: &TxOut,
+        ) -> Result<Vec<u8>, Error> {
+            let mut derivation = derivation.clone();
+            if derivation.account != 0 {
+                derivation.kix += 1;
+            }
+            self.0.sign(sighash, &derivation, address_type, spent)
+        }
+
+        fn derives_from_seed(&self) -> bool {
+            true
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Signers
+//!
+//! Signatures of sighashes by a key identified with its derivation,
+//! made by the Unlocker or by an external device, HSM or remote cosigner
+//!
+
+use std::sync::Arc;
+
+use bitcoin::{
+    util::bip32::{ChildNumber, ExtendedPrivKey},
+    PublicKey, TxOut,
+};
+
+use account::{coin_type, AccountAddressType, KeyDerivation, Unlocker};
+use context::SecpContext;
+use error::Error;
+
+/// signer of sighashes with keys of accounts
+pub trait Signer {
+    /// sign a sighash with the key of the derivation for an input spending the output
+    /// returns a DER encoded ECDSA signature, or a 64 byte BIP340 signature for P2TR,
+    /// without hash type
+    fn sign(
+        &mut self,
+        sighash: &[u8],
+        derivation: &KeyDerivation,
+        address_type: AccountAddressType,
+        spent: &TxOut,
+    ) -> Result<Vec<u8>, Error>;
+
+    /// sign a sighash with an imported key given by its encrypted private key
+    fn sign_imported(
+        &mut self,
+        _sighash: &[u8],
+        _derivation: &KeyDerivation,
+        _address_type: AccountAddressType,
+        _public: &PublicKey,
+        _encrypted: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("signer does not hold imported keys"))
+    }
+
+    /// true if keys are derived from the seed of the master account
+    /// such a signer can not sign for watch-only accounts
+    fn derives_from_seed(&self) -> bool {
+        false
+    }
+}
+
+impl Signer for Unlocker {
+    fn sign(
+        &mut self,
+        sighash: &[u8],
+        derivation: &KeyDerivation,
+        address_type: AccountAddressType,
+        _spent: &TxOut,
+    ) -> Result<Vec<u8>, Error> {
+        let mut key = self.unlock_derivation(address_type, derivation)?;
+        self.count_signature();
+        let context = self.context();
+        if address_type == AccountAddressType::P2TR {
+            context.taproot_tweak_private(&mut key)?;
+            return Ok(context.schnorr_sign(sighash, &key)?.to_vec());
+        }
+        Ok(context.sign(sighash, &key)?.serialize_der().to_vec())
+    }
+
+    fn sign_imported(
+        &mut self,
+        sighash: &[u8],
+        derivation: &KeyDerivation,
+        address_type: AccountAddressType,
+        public: &PublicKey,
+        encrypted: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let key = self.decrypt_imported(
+            address_type,
+            derivation.account,
+            derivation.sub,
+            public,
+            encrypted,
+        )?;
+        self.count_signature();
+        Ok(self.context().sign(sighash, &key)?.serialize_der().to_vec())
+    }
+
+    fn derives_from_seed(&self) -> bool {
+        true
+    }
+}
+
+/// a signer holding an extended private key in memory, as a device would
+/// it derives keys of the BIP44, BIP48 or BIP86 path of a request, or its custom path,
+/// and logs the requests
+pub struct MockSigner {
+    master_private: ExtendedPrivKey,
+    context: Arc<SecpContext>,
+    /// sighash, derivation and address type of all requests in their order
+    pub requests: Vec<(Vec<u8>, KeyDerivation, AccountAddressType)>,
+}
+
+impl MockSigner {
+    /// a signer with the master key of a device
+    pub fn new(master_private: ExtendedPrivKey) -> MockSigner {
+        MockSigner {
+            master_private,
+            context: Arc::new(SecpContext::new()),
+            requests: Vec::new(),
+        }
+    }
+}
+
+impl Signer for MockSigner {
+    fn sign(
+        &mut self,
+        sighash: &[u8],
+        derivation: &KeyDerivation,
+        address_type: AccountAddressType,
+        _spent: &TxOut,
+    ) -> Result<Vec<u8>, Error> {
+        self.requests
+            .push((sighash.to_vec(), derivation.clone(), address_type));
+        if derivation.imported {
+            return Err(Error::Unsupported("imported keys are not derived"));
+        }
+        let mut path = match derivation.path {
+            Some(ref path) => path.into_iter().cloned().collect(),
+            None => {
+                let mut path = vec![
+                    ChildNumber::Hardened {
+                        index: address_type.as_u32(),
+                    },
+                    ChildNumber::Hardened {
+                        index: coin_type(self.master_private.network),
+                    },
+                    ChildNumber::Hardened {
+                        index: derivation.account,
+                    },
+                ];
+                if let Some(script_type) = address_type.script_type() {
+                    path.push(ChildNumber::Hardened { index: script_type });
+                }
+                path.push(ChildNumber::Normal {
+                    index: derivation.sub,
+                });
+                path
+            }
+        };
+        path.push(ChildNumber::Normal {
+            index: derivation.kix,
+        });
+        let mut key = path
+            .into_iter()
+            .try_fold(self.master_private, |k, c| {
+                self.context.private_child(&k, c)
+            })?
+            .private_key;
+        if let Some(ref tweak) = derivation.tweak {
+            self.context.tweak_add(&mut key, tweak.as_slice())?;
+        }
+        if address_type == AccountAddressType::P2TR {
+            self.context.taproot_tweak_private(&mut key)?;
+            return Ok(self.context.schnorr_sign(sighash, &key)?.to_vec());
+        }
+        Ok(self.context.sign(sighash, &key)?.serialize_der().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{
+        blockdata::transaction::SigHashType, network::constants::Network, OutPoint, Script,
+        Transaction, TxIn,
+    };
+    use bitcoin_hashes::sha256d;
+
+    use account::{Account, MasterAccount};
+
+    use super::*;
+
+    fn watching(device: &ExtendedPrivKey) -> MasterAccount {
+        let context = SecpContext::new();
+        let mut master = MasterAccount::watch_only(context.extended_public_from_private(device), 0);
+        for (n, address_type) in [AccountAddressType::P2WPKH, AccountAddressType::P2TR]
+            .iter()
+            .enumerate()
+        {
+            let account_private =
+                [address_type.as_u32(), 1, n as u32]
+                    .iter()
+                    .fold(*device, |k, i| {
+                        context
+                            .private_child(&k, ChildNumber::Hardened { index: *i })
+                            .unwrap()
+                    });
+            master.add_account(
+                Account::new_watch_only(
+                    &context.extended_public_from_private(&account_private),
+                    *address_type,
+                    n as u32,
+                    0,
+                    10,
+                )
+                .unwrap(),
+            );
+        }
+        master
+    }
+
+    #[test]
+    fn mock_signer() {
+        let device = ExtendedPrivKey::new_master(Network::Testnet, &[0x42; 32]).unwrap();
+        let master = watching(&device);
+        let spent = (0..2)
+            .map(|n| TxOut {
+                script_pubkey: master
+                    .get((n, 0))
+                    .unwrap()
+                    .get_key(3)
+                    .unwrap()
+                    .address
+                    .script_pubkey(),
+                value: 1000,
+            })
+            .collect::<Vec<_>>();
+        let mut transaction = Transaction {
+            input: (0..2)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint {
+                        txid: sha256d::Hash::default(),
+                        vout,
+                    },
+                    sequence: 0xffffffff,
+                    witness: Vec::new(),
+                    script_sig: Script::new(),
+                })
+                .collect(),
+            output: vec![spent[0].clone()],
+            lock_time: 0,
+            version: 2,
+        };
+        let resolver = |point: &OutPoint| spent.get(point.vout as usize).cloned();
+
+        let mut signer = MockSigner::new(device);
+        assert_eq!(
+            master
+                .sign(&mut transaction, SigHashType::All, &resolver, &mut signer)
+                .unwrap(),
+            2
+        );
+        assert_eq!(signer.requests.len(), 2);
+        assert!(signer.requests.iter().all(|(_, d, _)| d.kix == 3));
+        assert!(signer
+            .requests
+            .iter()
+            .any(|(_, _, t)| *t == AccountAddressType::P2TR));
+        // a schnorr signature of SIGHASH_DEFAULT and a DER signature with public key
+        assert!(transaction
+            .input
+            .iter()
+            .any(|i| i.witness.len() == 1 && i.witness[0].len() == 64));
+        assert!(transaction.input.iter().any(|i| i.witness.len() == 2));
+
+        // signatures of an other device are rejected
+        let other = ExtendedPrivKey::new_master(Network::Testnet, &[0x43; 32]).unwrap();
+        let signer: &mut dyn Signer = &mut MockSigner::new(other);
+        assert!(master
+            .sign(&mut transaction, SigHashType::All, &resolver, signer)
+            .is_err());
+    }
+}
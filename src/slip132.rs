@@ -0,0 +1,338 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # SLIP-132 extended keys
+//!
+//! Extended keys with version bytes that tell the script type
+//! xpub/ypub/zpub/Ypub/Zpub and their testnet counterparts tpub/upub/vpub/Upub/Vpub
+//!
+
+use std::str::FromStr;
+
+use bitcoin::{
+    network::constants::Network,
+    util::base58,
+    util::bip32::{ExtendedPrivKey, ExtendedPubKey},
+};
+
+use account::{Account, AccountAddressType};
+use error::Error;
+
+/// script type of an extended key as told by its SLIP-132 version
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyVersion {
+    /// xpub, tpub: P2PKH or P2SH
+    Legacy,
+    /// ypub, upub: P2WPKH nested in P2SH
+    NestedSegwit,
+    /// zpub, vpub: P2WPKH
+    Segwit,
+    /// Ypub, Upub: multisig P2WSH nested in P2SH
+    NestedMultisig,
+    /// Zpub, Vpub: multisig P2WSH
+    Multisig,
+}
+
+/// (version, mainnet, public, private)
+const VERSIONS: [(KeyVersion, bool, [u8; 4], [u8; 4]); 10] = [
+    (
+        KeyVersion::Legacy,
+        true,
+        [0x04, 0x88, 0xb2, 0x1e],
+        [0x04, 0x88, 0xad, 0xe4],
+    ),
+    (
+        KeyVersion::NestedSegwit,
+        true,
+        [0x04, 0x9d, 0x7c, 0xb2],
+        [0x04, 0x9d, 0x78, 0x78],
+    ),
+    (
+        KeyVersion::Segwit,
+        true,
+        [0x04, 0xb2, 0x47, 0x46],
+        [0x04, 0xb2, 0x43, 0x0c],
+    ),
+    (
+        KeyVersion::NestedMultisig,
+        true,
+        [0x02, 0x95, 0xb4, 0x3f],
+        [0x02, 0x95, 0xb0, 0x05],
+    ),
+    (
+        KeyVersion::Multisig,
+        true,
+        [0x02, 0xaa, 0x7e, 0xd3],
+        [0x02, 0xaa, 0x7a, 0x99],
+    ),
+    (
+        KeyVersion::Legacy,
+        false,
+        [0x04, 0x35, 0x87, 0xcf],
+        [0x04, 0x35, 0x83, 0x94],
+    ),
+    (
+        KeyVersion::NestedSegwit,
+        false,
+        [0x04, 0x4a, 0x52, 0x62],
+        [0x04, 0x4a, 0x4e, 0x28],
+    ),
+    (
+        KeyVersion::Segwit,
+        false,
+        [0x04, 0x5f, 0x1c, 0xf6],
+        [0x04, 0x5f, 0x18, 0xbc],
+    ),
+    (
+        KeyVersion::NestedMultisig,
+        false,
+        [0x02, 0x42, 0x89, 0xef],
+        [0x02, 0x42, 0x85, 0xb5],
+    ),
+    (
+        KeyVersion::Multisig,
+        false,
+        [0x02, 0x57, 0x54, 0x83],
+        [0x02, 0x57, 0x50, 0x48],
+    ),
+];
+
+impl KeyVersion {
+    /// the version used for keys of an account address type
+    /// SLIP-132 has no version for P2WSH accounts of arbitrary scripts
+    pub fn from_address_type(address_type: AccountAddressType) -> Result<KeyVersion, Error> {
+        match address_type {
+            AccountAddressType::P2PKH => Ok(KeyVersion::Legacy),
+            AccountAddressType::P2SHWPKH => Ok(KeyVersion::NestedSegwit),
+            AccountAddressType::P2WPKH => Ok(KeyVersion::Segwit),
+            AccountAddressType::P2WSH(_) | AccountAddressType::P2SHWSH(_) => Err(
+                Error::Unsupported("SLIP-132 version of P2WSH accounts other than multisig"),
+            ),
+            AccountAddressType::P2WSHMultisig => Ok(KeyVersion::Multisig),
+            AccountAddressType::P2SHWSHMultisig => Ok(KeyVersion::NestedMultisig),
+            // SLIP-132 has no version for taproot, BIP86 uses xpub
+            AccountAddressType::P2TR => Ok(KeyVersion::Legacy),
+        }
+    }
+
+    fn bytes(self, network: Network, public: bool) -> [u8; 4] {
+        let mainnet = network == Network::Bitcoin;
+        let (_, _, p, s) = VERSIONS
+            .iter()
+            .find(|(v, m, _, _)| *v == self && *m == mainnet)
+            .unwrap();
+        if public {
+            *p
+        } else {
+            *s
+        }
+    }
+}
+
+impl Account {
+    /// the extended public key of this account with SLIP-132 version of its address type
+    pub fn slip132_public(&self) -> Result<String, Error> {
+        Ok(encode_public(
+            self.master_public(),
+            KeyVersion::from_address_type(self.address_type())?,
+        ))
+    }
+}
+
+/// serialize an extended public key with SLIP-132 version
+pub fn encode_public(key: &ExtendedPubKey, version: KeyVersion) -> String {
+    replace_version(&key.to_string(), version.bytes(key.network, true))
+}
+
+/// serialize an extended private key with SLIP-132 version
+pub fn encode_private(key: &ExtendedPrivKey, version: KeyVersion) -> String {
+    replace_version(&key.to_string(), version.bytes(key.network, false))
+}
+
+/// parse an extended public key of any SLIP-132 version
+/// SLIP-132 versions do not tell testnet from regtest, non-mainnet keys are read as testnet
+/// use decode_public_for_network for regtest keys
+pub fn decode_public(s: &str) -> Result<(ExtendedPubKey, KeyVersion), Error> {
+    let (_, mainnet) = version_of(s, true)?;
+    decode_public_for_network(s, default_network(mainnet))
+}
+
+/// parse an extended public key of any SLIP-132 version of a network
+pub fn decode_public_for_network(
+    s: &str,
+    network: Network,
+) -> Result<(ExtendedPubKey, KeyVersion), Error> {
+    let (version, mainnet) = version_of(s, true)?;
+    if mainnet != (network == Network::Bitcoin) {
+        return Err(Error::Network);
+    }
+    let mut key =
+        ExtendedPubKey::from_str(&replace_version(s, KeyVersion::Legacy.bytes(network, true)))?;
+    key.network = network;
+    Ok((key, version))
+}
+
+/// parse an extended private key of any SLIP-132 version
+/// SLIP-132 versions do not tell testnet from regtest, non-mainnet keys are read as testnet
+/// use decode_private_for_network for regtest keys
+pub fn decode_private(s: &str) -> Result<(ExtendedPrivKey, KeyVersion), Error> {
+    let (_, mainnet) = version_of(s, false)?;
+    decode_private_for_network(s, default_network(mainnet))
+}
+
+/// parse an extended private key of any SLIP-132 version of a network
+pub fn decode_private_for_network(
+    s: &str,
+    network: Network,
+) -> Result<(ExtendedPrivKey, KeyVersion), Error> {
+    let (version, mainnet) = version_of(s, false)?;
+    if mainnet != (network == Network::Bitcoin) {
+        return Err(Error::Network);
+    }
+    let mut key = ExtendedPrivKey::from_str(&replace_version(
+        s,
+        KeyVersion::Legacy.bytes(network, false),
+    ))?;
+    key.network = network;
+    Ok((key, version))
+}
+
+fn default_network(mainnet: bool) -> Network {
+    if mainnet {
+        Network::Bitcoin
+    } else {
+        Network::Testnet
+    }
+}
+
+fn version_of(s: &str, public: bool) -> Result<(KeyVersion, bool), Error> {
+    let data = base58::from_check(s)?;
+    if data.len() != 78 {
+        return Err(Error::Base58(base58::Error::InvalidLength(data.len())));
+    }
+    VERSIONS
+        .iter()
+        .find(|(_, _, p, s)| if public { *p } else { *s } == data[0..4])
+        .map(|(v, m, _, _)| (*v, *m))
+        .ok_or_else(|| Error::Base58(base58::Error::InvalidVersion(data[0..4].to_vec())))
+}
+
+fn replace_version(s: &str, version: [u8; 4]) -> String {
+    let mut data = base58::from_check(s).expect("valid extended key");
+    data[0..4].copy_from_slice(&version[..]);
+    base58::check_encode_slice(data.as_slice())
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::util::bip32::ChildNumber;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+    use context::SecpContext;
+    use mnemonic::Mnemonic;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn bip84_zpub() {
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let master = MasterAccount::from_mnemonic(
+            &Mnemonic::from_str(words).unwrap(),
+            0,
+            Network::Bitcoin,
+            PASSPHRASE,
+            None,
+        )
+        .unwrap();
+        let unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let context = SecpContext::new();
+        let account_key = [84, 0, 0].iter().fold(*unlocker.master_private(), |k, i| {
+            context
+                .private_child(&k, ChildNumber::Hardened { index: *i })
+                .unwrap()
+        });
+        let zpub = encode_public(
+            &context.extended_public_from_private(&account_key),
+            KeyVersion::Segwit,
+        );
+        assert_eq!(zpub, "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs");
+        let (key, version) = decode_public(&zpub).unwrap();
+        assert_eq!(key, context.extended_public_from_private(&account_key));
+        assert_eq!(version, KeyVersion::Segwit);
+
+        let zprv = encode_private(&account_key, KeyVersion::Segwit);
+        assert!(zprv.starts_with("zprv"));
+        assert_eq!(
+            decode_private(&zprv).unwrap(),
+            (account_key, KeyVersion::Segwit)
+        );
+        assert!(decode_private(&zpub).is_err());
+    }
+
+    #[test]
+    fn account_versions() {
+        let master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Bitcoin, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let segwit = Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 0).unwrap();
+        assert!(segwit.slip132_public().unwrap().starts_with("zpub"));
+        // single key scripts of P2WSH accounts are not multisig
+        for address_type in [
+            AccountAddressType::P2WSH(4711),
+            AccountAddressType::P2SHWSH(4712),
+        ]
+        .iter()
+        {
+            let scripts = Account::new(&mut unlocker, *address_type, 0, 0, 0).unwrap();
+            assert!(scripts.slip132_public().is_err());
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        let key = ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4YUqaTmpewwbvSoA4dkwzGzvwGcUbwbRyu8i6dCSroCsvFmC6qzQgJxddMfA6Mg8r6XmkJVhQ8ihAWzfRBYTG5o28AC5HWX2").unwrap();
+        for (version, prefix) in [
+            (KeyVersion::Legacy, "tpub"),
+            (KeyVersion::NestedSegwit, "upub"),
+            (KeyVersion::Segwit, "vpub"),
+            (KeyVersion::NestedMultisig, "Upub"),
+            (KeyVersion::Multisig, "Vpub"),
+        ]
+        .iter()
+        {
+            let encoded = encode_public(&key, *version);
+            assert!(encoded.starts_with(prefix));
+            assert_eq!(decode_public(&encoded).unwrap(), (key, *version));
+        }
+    }
+
+    #[test]
+    fn regtest() {
+        let mut key = ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4YUqaTmpewwbvSoA4dkwzGzvwGcUbwbRyu8i6dCSroCsvFmC6qzQgJxddMfA6Mg8r6XmkJVhQ8ihAWzfRBYTG5o28AC5HWX2").unwrap();
+        key.network = Network::Regtest;
+        let encoded = encode_public(&key, KeyVersion::Segwit);
+        assert!(encoded.starts_with("vpub"));
+        // versions are shared with testnet
+        assert_eq!(decode_public(&encoded).unwrap().0.network, Network::Testnet);
+        assert_eq!(
+            decode_public_for_network(&encoded, Network::Regtest).unwrap(),
+            (key, KeyVersion::Segwit)
+        );
+        assert!(decode_public_for_network(&encoded, Network::Bitcoin).is_err());
+    }
+}
@@ -23,7 +23,7 @@ use std::{
 };
 
 use bitcoin::util::bip158::{BitStreamReader, BitStreamWriter};
-use crypto::{hmac::Hmac, mac::Mac, pbkdf2::pbkdf2, sha2::Sha256};
+use crypto::{hmac::Hmac, mac::Mac, pbkdf2::pbkdf2, sha2::Sha256, util::secure_memset};
 use rand::{thread_rng, RngCore};
 
 use account::Seed;
@@ -173,12 +173,13 @@ impl ShamirSecretSharing {
                 )?,
             ));
         }
-        Ok(Seed(Self::decrypt(
-            id,
-            iteration_exponent,
-            Self::recover_secret(group_threshold, group_secrets.as_slice())?.as_slice(),
-            pd_passphrase,
-        )?))
+        let mut encrypted = Self::recover_secret(group_threshold, group_secrets.as_slice())?;
+        for (_, group_secret) in group_secrets.iter_mut() {
+            secure_memset(group_secret.as_mut_slice(), 0);
+        }
+        let seed = Self::decrypt(id, iteration_exponent, encrypted.as_slice(), pd_passphrase);
+        secure_memset(encrypted.as_mut_slice(), 0);
+        Ok(Seed(seed?))
     }
 
     fn preprocess(
@@ -453,8 +454,13 @@ impl ShamirSecretSharing {
             left.as_mut_slice().copy_from_slice(right.as_slice());
             right.as_mut_slice().copy_from_slice(output.as_slice());
         }
-        right.extend_from_slice(left.as_slice());
-        right
+        let mut result = Vec::with_capacity(len);
+        result.extend_from_slice(right.as_slice());
+        result.extend_from_slice(left.as_slice());
+        secure_memset(left.as_mut_slice(), 0);
+        secure_memset(right.as_mut_slice(), 0);
+        secure_memset(output.as_mut_slice(), 0);
+        result
     }
 
     // a step of a Feistel network
@@ -466,10 +472,13 @@ impl ShamirSecretSharing {
         passphrase: &str,
         output: &mut [u8],
     ) {
-        let mut key = [step].to_vec();
+        // reserved not to leave copies behind on growth
+        let mut key = Vec::with_capacity(1 + passphrase.len());
+        key.push(step);
         key.extend_from_slice(passphrase.as_bytes());
         let mut mac = Hmac::new(Sha256::new(), key.as_slice());
-        let mut salt = "shamir".as_bytes().to_vec();
+        let mut salt = Vec::with_capacity(8 + block.len());
+        salt.extend_from_slice("shamir".as_bytes());
         salt.extend_from_slice(&[(id >> 8) as u8, (id & 0xff) as u8]);
         salt.extend_from_slice(block);
         pbkdf2(
@@ -478,6 +487,8 @@ impl ShamirSecretSharing {
             ((BASE_ITERATION_COUNT / 4) as u32) << (iteration_exponent as u32),
             output,
         );
+        secure_memset(key.as_mut_slice(), 0);
+        secure_memset(salt.as_mut_slice(), 0);
     }
 
     const EXP: [u8; 255] = [
@@ -699,7 +710,13 @@ mod test {
         let reconstructed_master =
             MasterAccount::from_seed(&reconstructed_seed, 0, Network::Bitcoin, PASSPHRASE).unwrap();
         assert_eq!(master.master_public(), reconstructed_master.master_public());
-        assert_eq!(master.encrypted(), reconstructed_master.encrypted());
+        // encryption is salted, compare the seeds
+        assert_eq!(
+            reconstructed_master
+                .seed(Network::Bitcoin, PASSPHRASE)
+                .unwrap(),
+            seed
+        );
     }
 
     #[test]
@@ -828,6 +845,7 @@ mod test {
                         ShamirSecretSharing::combine(&shares, Some("TREZOR"))
                             .unwrap()
                             .0
+                            .as_slice()
                     )
                 );
             }
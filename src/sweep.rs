@@ -0,0 +1,339 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Sweep
+//!
+//! Move coins of imported private keys, e.g. of paper wallets, into an account
+//!
+
+use bitcoin::{
+    blockdata::{opcodes::all, script::Builder, transaction::SigHashType},
+    util::bip143,
+    Address, OutPoint, PrivateKey, PublicKey, Script, Transaction, TxIn, TxOut,
+};
+use bitcoin_hashes::{hash160, Hash};
+
+use account::{bip143_sighash, Account, AccountAddressType};
+use context::SecpContext;
+use error::Error;
+
+const RBF: u32 = 0xffff_fffd;
+/// smallest output created
+const DUST: u64 = 546;
+
+/// builder of a transaction sweeping coins of private keys in wallet import format
+/// coins of the P2PKH address of a key and, for compressed keys, of its P2SHWPKH and P2WPKH
+/// addresses can be swept
+pub struct SweepBuilder {
+    keys: Vec<PrivateKey>,
+    coins: Vec<(OutPoint, TxOut)>,
+    fee_rate: u64,
+    context: SecpContext,
+}
+
+impl SweepBuilder {
+    /// a sweep paying fee_rate satoshi per virtual byte
+    pub fn new(fee_rate: u64) -> SweepBuilder {
+        SweepBuilder {
+            keys: Vec::new(),
+            coins: Vec::new(),
+            fee_rate,
+            context: SecpContext::new(),
+        }
+    }
+
+    /// add a private key in wallet import format
+    pub fn add_wif(&mut self, wif: &str) -> Result<&mut SweepBuilder, Error> {
+        let key = PrivateKey::from_wif(wif).map_err(|_| Error::Unsupported("invalid WIF key"))?;
+        self.keys.push(key);
+        Ok(self)
+    }
+
+    /// add an unspent output of one of the keys
+    pub fn add_coin(&mut self, point: OutPoint, output: TxOut) -> &mut SweepBuilder {
+        self.coins.push((point, output));
+        self
+    }
+
+    /// a signed transaction spending all coins to the next key of the account
+    pub fn build(&self, account: &mut Account) -> Result<Transaction, Error> {
+        if self.coins.is_empty() {
+            return Err(Error::Unsupported("nothing to sweep"));
+        }
+        if self.keys.iter().any(|k| k.network != account.network()) {
+            return Err(Error::Network);
+        }
+        let spending = self
+            .coins
+            .iter()
+            .map(|(_, output)| {
+                self.spending_key(&output.script_pubkey)
+                    .ok_or(Error::Unsupported("coin is not of the keys"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut transaction = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: self
+                .coins
+                .iter()
+                .map(|(point, _)| TxIn {
+                    previous_output: *point,
+                    script_sig: Script::new(),
+                    sequence: RBF,
+                    witness: Vec::new(),
+                })
+                .collect(),
+            // the key is taken once the sweep is known to succeed
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: Script::from(vec![0u8; script_len(account.address_type())]),
+            }],
+        };
+        // size with signatures of maximal length
+        let mut estimate = transaction.clone();
+        for (input, (key, address_type)) in estimate.input.iter_mut().zip(spending.iter()) {
+            let public = self.context.public_from_private(key);
+            satisfy(input, vec![0u8; 73], &public, *address_type);
+        }
+        // u64::div_ceil needs Rust 1.73
+        #[allow(clippy::manual_div_ceil)]
+        let fee = (estimate.get_weight() as u64 + 3) / 4 * self.fee_rate;
+        let total = self.coins.iter().map(|(_, o)| o.value).sum::<u64>();
+        if total < fee + DUST {
+            return Err(Error::Unsupported("swept amount does not cover the fee"));
+        }
+        transaction.output[0].value = total - fee;
+        transaction.output[0].script_pubkey = account.next_key()?.address.script_pubkey();
+
+        let txclone = transaction.clone();
+        let hasher = bip143::SighashComponents::new(&txclone);
+        for (ix, (input, (key, address_type))) in transaction
+            .input
+            .iter_mut()
+            .zip(spending.iter())
+            .enumerate()
+        {
+            let public = self.context.public_from_private(key);
+            let spent = &self.coins[ix].1;
+            let sighash = match address_type {
+                AccountAddressType::P2PKH => {
+                    txclone.signature_hash(ix, &spent.script_pubkey, SigHashType::All.as_u32())
+                }
+                _ => bip143_sighash(
+                    &hasher,
+                    &txclone,
+                    ix,
+                    &p2pkh_script(&public),
+                    spent.value,
+                    SigHashType::All,
+                ),
+            };
+            let mut signature = self
+                .context
+                .sign(&sighash[..], key)?
+                .serialize_der()
+                .to_vec();
+            signature.push(SigHashType::All.as_u32() as u8);
+            satisfy(input, signature, &public, *address_type);
+        }
+        Ok(transaction)
+    }
+
+    /// the key and address type of a coin
+    fn spending_key(&self, script_pubkey: &Script) -> Option<(&PrivateKey, AccountAddressType)> {
+        for key in &self.keys {
+            let public = self.context.public_from_private(key);
+            let mut types = vec![AccountAddressType::P2PKH];
+            if public.compressed {
+                types.push(AccountAddressType::P2SHWPKH);
+                types.push(AccountAddressType::P2WPKH);
+            }
+            for address_type in types {
+                let address = match address_type {
+                    AccountAddressType::P2PKH => Address::p2pkh(&public, key.network),
+                    AccountAddressType::P2SHWPKH => Address::p2shwpkh(&public, key.network),
+                    _ => Address::p2wpkh(&public, key.network),
+                };
+                if address.script_pubkey() == *script_pubkey {
+                    return Some((key, address_type));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// length of the pubkey script of an address type
+fn script_len(address_type: AccountAddressType) -> usize {
+    match address_type {
+        AccountAddressType::P2PKH => 25,
+        AccountAddressType::P2SHWPKH
+        | AccountAddressType::P2SHWSH(_)
+        | AccountAddressType::P2SHWSHMultisig => 23,
+        AccountAddressType::P2WPKH => 22,
+        AccountAddressType::P2WSH(_)
+        | AccountAddressType::P2WSHMultisig
+        | AccountAddressType::P2TR => 34,
+    }
+}
+
+fn p2pkh_script(public: &PublicKey) -> Script {
+    Builder::new()
+        .push_opcode(all::OP_DUP)
+        .push_opcode(all::OP_HASH160)
+        .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
+        .push_opcode(all::OP_EQUALVERIFY)
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+}
+
+/// set script_sig and witness of an input with the signature of the key
+fn satisfy(
+    input: &mut TxIn,
+    signature: Vec<u8>,
+    public: &PublicKey,
+    address_type: AccountAddressType,
+) {
+    match address_type {
+        AccountAddressType::P2PKH => {
+            input.script_sig = Builder::new()
+                .push_slice(signature.as_slice())
+                .push_slice(public.to_bytes().as_slice())
+                .into_script();
+        }
+        _ => {
+            if address_type == AccountAddressType::P2SHWPKH {
+                input.script_sig = Builder::new()
+                    .push_slice(
+                        &Builder::new()
+                            .push_int(0)
+                            .push_slice(&hash160::Hash::hash(public.to_bytes().as_slice())[..])
+                            .into_script()[..],
+                    )
+                    .into_script();
+            }
+            input.witness = vec![signature, public.to_bytes()];
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use bitcoin::network::constants::Network;
+    use bitcoin_hashes::sha256d;
+    use secp256k1::key::SecretKey;
+
+    use account::{MasterAccount, MasterKeyEntropy, Unlocker};
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    #[test]
+    fn sweep() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        master.add_account(
+            Account::new(&mut unlocker, AccountAddressType::P2WPKH, 0, 0, 10).unwrap(),
+        );
+        let context = SecpContext::new();
+        let key = |n: u8, compressed| PrivateKey {
+            compressed,
+            network: Network::Testnet,
+            key: SecretKey::from_slice(&[n; 32]).unwrap(),
+        };
+        let paper = [
+            (key(1, false), AccountAddressType::P2PKH),
+            (key(2, true), AccountAddressType::P2PKH),
+            (key(3, true), AccountAddressType::P2SHWPKH),
+            (key(4, true), AccountAddressType::P2WPKH),
+        ];
+        let mut builder = SweepBuilder::new(10);
+        let mut spent = HashMap::new();
+        for (n, (key, address_type)) in paper.iter().enumerate() {
+            let public = context.public_from_private(key);
+            let address = match address_type {
+                AccountAddressType::P2PKH => Address::p2pkh(&public, Network::Testnet),
+                AccountAddressType::P2SHWPKH => Address::p2shwpkh(&public, Network::Testnet),
+                _ => Address::p2wpkh(&public, Network::Testnet),
+            };
+            let point = OutPoint {
+                txid: sha256d::Hash::hash(&[n as u8]),
+                vout: n as u32,
+            };
+            let output = TxOut {
+                value: 100000,
+                script_pubkey: address.script_pubkey(),
+            };
+            spent.insert(point, output.clone());
+            builder
+                .add_wif(&key.to_wif())
+                .unwrap()
+                .add_coin(point, output);
+        }
+        // a sweep not covering its fee does not take a key
+        let mut expensive = SweepBuilder::new(1000);
+        for (key, _) in paper.iter() {
+            expensive.add_wif(&key.to_wif()).unwrap();
+        }
+        let (point, output) = spent.iter().next().unwrap();
+        expensive.add_coin(*point, output.clone());
+        match expensive.build(master.get_mut((0, 0)).unwrap()) {
+            Err(Error::Unsupported("swept amount does not cover the fee")) => {}
+            _ => panic!("fee not checked"),
+        }
+        assert_eq!(master.get((0, 0)).unwrap().used(), 0);
+
+        let transaction = builder.build(master.get_mut((0, 0)).unwrap()).unwrap();
+        transaction
+            .verify(|point| spent.get(point).cloned())
+            .unwrap();
+        assert_eq!(transaction.output.len(), 1);
+        assert_eq!(
+            transaction.output[0].script_pubkey,
+            master
+                .get((0, 0))
+                .unwrap()
+                .get_key(0)
+                .unwrap()
+                .address
+                .script_pubkey()
+        );
+        let fee = 400000 - transaction.output[0].value;
+        // signatures are estimated at their maximal length
+        #[allow(clippy::manual_div_ceil)]
+        let vsize = (transaction.get_weight() as u64 + 3) / 4;
+        assert!(fee >= vsize * 10 && fee <= (vsize + 8) * 10);
+
+        // coins of other keys are refused
+        builder.add_coin(
+            OutPoint::default(),
+            TxOut {
+                value: 1000,
+                script_pubkey: Address::p2wpkh(
+                    &context.public_from_private(&key(5, true)),
+                    Network::Testnet,
+                )
+                .script_pubkey(),
+            },
+        );
+        assert!(builder.build(master.get_mut((0, 0)).unwrap()).is_err());
+    }
+}
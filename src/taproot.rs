@@ -0,0 +1,275 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Taproot
+//!
+//! Key path spending of BIP341 outputs: tagged hashes, signature hash
+//! and bech32m (BIP350) encoding of segwit version 1 addresses
+//!
+
+use bitcoin::{
+    bech32::{self, u5},
+    consensus::encode::serialize,
+    network::constants::Network,
+    util::address::{Address, Payload},
+    Transaction, TxOut,
+};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+
+use error::Error;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+
+/// hash of data with a tag as defined in BIP340
+pub fn tagged_hash(tag: &str, data: &[&[u8]]) -> sha256::Hash {
+    let mut engine = tagged_engine(tag);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine)
+}
+
+fn tagged_engine(tag: &str) -> sha256::HashEngine {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    engine
+}
+
+/// tweak of an internal key without script tree (BIP86)
+pub fn tweak(internal: &[u8]) -> sha256::Hash {
+    tagged_hash("TapTweak", &[internal])
+}
+
+/// segwit version 1 address of an output key
+pub fn address(output_key: &[u8; 32], network: Network) -> Address {
+    Address {
+        network,
+        payload: Payload::WitnessProgram {
+            version: u5::try_from_u8(1).expect("1<32"),
+            program: output_key.to_vec(),
+        },
+    }
+}
+
+/// string form of an address, bech32m for segwit version 1 and above
+/// the Display of Address only knows the bech32 checksum of version 0
+pub fn encode_address(address: &Address) -> String {
+    match address.payload {
+        Payload::WitnessProgram {
+            version,
+            ref program,
+        } if version.to_u8() > 0 => {
+            let mut data = vec![version.to_u8()];
+            data.extend(bech32::convert_bits(program.as_slice(), 8, 5, true).expect("8 to 5 bits"));
+            let hrp = hrp(address.network);
+            let checksum =
+                polymod(&[hrp_expand(hrp), data.clone(), vec![0u8; 6]].concat()) ^ BECH32M_CONST;
+            let mut s = String::from(hrp);
+            s.push('1');
+            for d in data {
+                s.push(CHARSET[d as usize] as char);
+            }
+            for i in 0..6 {
+                s.push(CHARSET[((checksum >> (5 * (5 - i))) & 31) as usize] as char);
+            }
+            s
+        }
+        _ => address.to_string(),
+    }
+}
+
+/// parse a bech32m address of segwit version 1 and above
+pub fn decode_address(s: &str) -> Result<Address, Error> {
+    if s.to_lowercase() != s && s.to_uppercase() != s {
+        return Err(Error::Bech32(bech32::Error::MixedCase));
+    }
+    let s = s.to_lowercase();
+    let sep = s
+        .rfind('1')
+        .ok_or(Error::Bech32(bech32::Error::MissingSeparator))?;
+    let (hrp, rest) = s.split_at(sep);
+    let network = match hrp {
+        "bc" => Network::Bitcoin,
+        "tb" => Network::Testnet,
+        "bcrt" => Network::Regtest,
+        _ => return Err(Error::Network),
+    };
+    let mut data = Vec::new();
+    for c in rest[1..].chars() {
+        match CHARSET.iter().position(|x| *x as char == c) {
+            Some(d) => data.push(d as u8),
+            None => return Err(Error::Bech32(bech32::Error::InvalidChar(c))),
+        }
+    }
+    if data.len() < 7 {
+        return Err(Error::Bech32(bech32::Error::InvalidLength));
+    }
+    if polymod(&[hrp_expand(hrp), data.clone()].concat()) != BECH32M_CONST {
+        return Err(Error::Bech32(bech32::Error::InvalidChecksum));
+    }
+    let version = data[0];
+    let program = bech32::convert_bits(&data[1..data.len() - 6], 5, 8, false)?;
+    if version == 0 || version > 16 || program.len() < 2 || program.len() > 40 {
+        return Err(Error::Bech32(bech32::Error::InvalidData(version)));
+    }
+    Ok(Address {
+        network,
+        payload: Payload::WitnessProgram {
+            version: u5::try_from_u8(version)?,
+            program,
+        },
+    })
+}
+
+fn hrp(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "bc",
+        Network::Testnet => "tb",
+        Network::Regtest => "bcrt",
+    }
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut v = hrp.bytes().map(|b| b >> 5).collect::<Vec<_>>();
+    v.push(0);
+    v.extend(hrp.bytes().map(|b| b & 31));
+    v
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+    let mut chk = 1u32;
+    for v in values {
+        let b = chk >> 25;
+        chk = (chk & 0x01ff_ffff) << 5 ^ u32::from(*v);
+        for (i, g) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+/// BIP341 signature hash of a key path spend without annex
+/// spent are the outputs spent by all inputs of the transaction in their order
+/// hash_type 0 (SIGHASH_DEFAULT) commits to the same as SIGHASH_ALL
+pub fn sighash(
+    transaction: &Transaction,
+    input_index: usize,
+    spent: &[TxOut],
+    hash_type: u8,
+) -> Result<sha256::Hash, Error> {
+    match hash_type {
+        0x00..=0x03 | 0x81..=0x83 => {}
+        _ => return Err(Error::Unsupported("invalid taproot sighash type")),
+    }
+    if spent.len() != transaction.input.len() || input_index >= transaction.input.len() {
+        return Err(Error::Unsupported(
+            "taproot sighash needs outputs spent by all inputs",
+        ));
+    }
+    let anyone_can_pay = hash_type & 0x80 != 0;
+    let base = hash_type & 0x03;
+
+    let mut engine = tagged_engine("TapSighash");
+    // epoch
+    engine.input(&[0u8, hash_type]);
+    engine.input(&serialize(&transaction.version));
+    engine.input(&serialize(&transaction.lock_time));
+    if !anyone_can_pay {
+        let mut prevouts = sha256::Hash::engine();
+        let mut amounts = sha256::Hash::engine();
+        let mut script_pubkeys = sha256::Hash::engine();
+        let mut sequences = sha256::Hash::engine();
+        for (input, spend) in transaction.input.iter().zip(spent.iter()) {
+            prevouts.input(&serialize(&input.previous_output));
+            amounts.input(&serialize(&spend.value));
+            script_pubkeys.input(&serialize(&spend.script_pubkey));
+            sequences.input(&serialize(&input.sequence));
+        }
+        engine.input(&sha256::Hash::from_engine(prevouts)[..]);
+        engine.input(&sha256::Hash::from_engine(amounts)[..]);
+        engine.input(&sha256::Hash::from_engine(script_pubkeys)[..]);
+        engine.input(&sha256::Hash::from_engine(sequences)[..]);
+    }
+    if base != 0x02 && base != 0x03 {
+        let mut outputs = sha256::Hash::engine();
+        for output in &transaction.output {
+            outputs.input(&serialize(output));
+        }
+        engine.input(&sha256::Hash::from_engine(outputs)[..]);
+    }
+    // spend type: key path, no annex
+    engine.input(&[0u8]);
+    if anyone_can_pay {
+        let input = &transaction.input[input_index];
+        engine.input(&serialize(&input.previous_output));
+        engine.input(&serialize(&spent[input_index].value));
+        engine.input(&serialize(&spent[input_index].script_pubkey));
+        engine.input(&serialize(&input.sequence));
+    } else {
+        engine.input(&serialize(&(input_index as u32)));
+    }
+    if base == 0x03 {
+        let output = transaction
+            .output
+            .get(input_index)
+            .ok_or(Error::Unsupported(
+                "SIGHASH_SINGLE without corresponding output",
+            ))?;
+        engine.input(&sha256::Hash::hash(&serialize(output))[..]);
+    }
+    Ok(sha256::Hash::from_engine(engine))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn bech32m() {
+        let program = [0x11u8; 32];
+        let addr = address(&program, Network::Testnet);
+        let s = encode_address(&addr);
+        assert!(s.starts_with("tb1p"));
+        assert_eq!(decode_address(&s).unwrap(), addr);
+        assert_eq!(decode_address(&s.to_uppercase()).unwrap(), addr);
+        // a bech32 checksum is not accepted for version 1
+        assert!(decode_address(&addr.to_string()).is_err());
+        // version 0 is left to bech32
+        let v0 = Address::from_str("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").unwrap();
+        assert_eq!(encode_address(&v0), v0.to_string());
+        // BIP350 test vector
+        let v1 = decode_address("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0")
+            .unwrap();
+        assert_eq!(
+            encode_address(&v1),
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0"
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//
+// Copyright 2019 Tamas Blummer
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+//!
+//! # Script templates
+//!
+//! Named scripts of P2WSH accounts, stored with the account so that
+//! look-ahead and restore from the seed regenerate the exact scripts
+//!
+
+use bitcoin::{
+    blockdata::{opcodes::all, script::Builder},
+    PublicKey, Script,
+};
+
+/// script of each key of a P2WSH account
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ScriptTemplate {
+    /// <key> OP_CHECKSIG
+    SingleKey,
+    /// <csv> OP_CSV OP_DROP <key> OP_CHECKSIG
+    /// spendable csv blocks after confirmation
+    CsvDelayedSingleKey(u16),
+}
+
+impl ScriptTemplate {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptTemplate::SingleKey => "single key",
+            ScriptTemplate::CsvDelayedSingleKey(_) => "CSV-delayed single key",
+        }
+    }
+
+    /// number of blocks the script can not be spent after confirmation
+    pub fn csv(&self) -> Option<u16> {
+        match self {
+            ScriptTemplate::SingleKey => None,
+            ScriptTemplate::CsvDelayedSingleKey(csv) => Some(*csv),
+        }
+    }
+
+    /// the script of a key
+    pub fn script(&self, key: &PublicKey) -> Script {
+        match self {
+            ScriptTemplate::SingleKey => Builder::new(),
+            ScriptTemplate::CsvDelayedSingleKey(csv) => Builder::new()
+                .push_int(i64::from(*csv))
+                .push_opcode(all::OP_CSV)
+                .push_opcode(all::OP_DROP),
+        }
+        .push_slice(key.to_bytes().as_slice())
+        .push_opcode(all::OP_CHECKSIG)
+        .into_script()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{network::constants::Network, OutPoint, SigHashType, Transaction, TxIn, TxOut};
+    use bitcoin_hashes::sha256d;
+
+    use account::{Account, AccountAddressType, MasterAccount, MasterKeyEntropy, Unlocker};
+    use coins::Coins;
+
+    use super::*;
+
+    const PASSPHRASE: &str = "correct horse battery staple";
+    const CSV: u16 = 10;
+
+    #[test]
+    fn csv_delayed() {
+        let mut master =
+            MasterAccount::new(MasterKeyEntropy::Sufficient, Network::Testnet, PASSPHRASE).unwrap();
+        let mut unlocker = Unlocker::new_for_master(&master, PASSPHRASE).unwrap();
+        let template = ScriptTemplate::CsvDelayedSingleKey(CSV);
+        let account = Account::new_with_template(
+            &mut unlocker,
+            AccountAddressType::P2WSH(4711),
+            0,
+            0,
+            template,
+            5,
+        )
+        .unwrap();
+        assert_eq!(account.instantiated().len(), 5);
+        for (kix, i) in account.instantiated().iter().enumerate() {
+            assert_eq!(
+                i.script_code,
+                template.script(&account.compute_base_public_key(kix as u32).unwrap())
+            );
+            assert_eq!(i.csv, Some(CSV));
+        }
+        assert!(Account::new_with_template(
+            &mut unlocker,
+            AccountAddressType::P2WPKH,
+            1,
+            0,
+            template,
+            5
+        )
+        .is_err());
+
+        // restored from storage the account continues with the same scripts
+        let stored = serde_json::to_string(&account).unwrap();
+        let mut restored: Account = serde_json::from_str(&stored).unwrap();
+        assert_eq!(restored.template(), Some(template));
+        restored.do_look_ahead(Some(6)).unwrap();
+        let fresh = Account::new_with_template(
+            &mut unlocker,
+            AccountAddressType::P2WSH(4711),
+            0,
+            0,
+            template,
+            11,
+        )
+        .unwrap();
+        assert_eq!(
+            restored.get_scripts().collect::<Vec<_>>(),
+            fresh.get_scripts().collect::<Vec<_>>()
+        );
+        master.add_account(account);
+
+        // coins paid to a look-ahead script are found and spent after the delay
+        let funding = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: sha256d::Hash::default(),
+                    vout: 0,
+                },
+                sequence: 0xffffffff,
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: vec![TxOut {
+                script_pubkey: fresh.get_key(3).unwrap().address.script_pubkey(),
+                value: 100000,
+            }],
+            lock_time: 0,
+            version: 2,
+        };
+        let mut coins = Coins::new();
+        assert!(coins.process_unconfirmed_transaction(&mut master, &funding));
+        assert_eq!(master.get((0, 0)).unwrap().instantiated().len(), 8);
+
+        let mut spending = Transaction {
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: funding.txid(),
+                    vout: 0,
+                },
+                sequence: u32::from(CSV),
+                witness: Vec::new(),
+                script_sig: Script::new(),
+            }],
+            output: funding.output.clone(),
+            lock_time: 0,
+            version: 2,
+        };
+        assert_eq!(
+            master
+                .sign(
+                    &mut spending,
+                    SigHashType::All,
+                    &(|_: &OutPoint| Some(funding.output[0].clone())),
+                    &mut unlocker
+                )
+                .unwrap(),
+            1
+        );
+        spending
+            .verify(|_| Some(funding.output[0].clone()))
+            .unwrap();
+    }
+}